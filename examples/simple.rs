@@ -1,4 +1,4 @@
-use wgpu_font_renderer::{FontStore, TextRenderer, TypeWriter};
+use wgpu_font_renderer::{AtlasConfig, FontStore, TextRenderer, TypeWriter};
 
 use wgpu::{
     CommandEncoderDescriptor, CompositeAlphaMode, DeviceDescriptor, Features, Instance,
@@ -67,7 +67,8 @@ async fn run() {
     };
     surface.configure(&device, &config);
 
-    let mut font_store = FontStore::new(&device, &config);
+    let atlas_config = AtlasConfig { backend: Some(adapter.get_info().backend), ..AtlasConfig::default() };
+    let mut font_store = FontStore::with_atlas_config(&device, atlas_config);
     let cache_preset = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789,;:!ù*^$=)àç_è-('\"é&²<>+°§/.? ";
     let font_key = font_store.load(&device, &queue, "examples/Roboto-Regular.ttf", cache_preset).expect("Couldn't load the font");
 
@@ -78,7 +79,7 @@ async fn run() {
         paragraphs.push(paragraph);
     }
 
-    let mut text_renderer = TextRenderer::new(&device, &config, font_store.atlas());
+    let mut text_renderer = TextRenderer::new(&device, &config, &font_store);
 
     let _physical_width = (width as f64 * scale_factor) as f32;
     let _physical_height = (height as f64 * scale_factor) as f32;
@@ -101,7 +102,9 @@ async fn run() {
                     WindowEvent::RedrawRequested => {
                         
                         // Prepare should happen here
-                        text_renderer.prepare(&device, &paragraphs, &font_store);
+                        if let Err(error) = text_renderer.prepare(&device, &queue, &paragraphs, &mut font_store) {
+                            eprintln!("Some glyphs couldn't be cached: {error}");
+                        }
                         
                         let frame = surface.get_current_texture().unwrap();
                         let view = frame.texture.create_view(&TextureViewDescriptor::default());
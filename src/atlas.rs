@@ -1,9 +1,13 @@
 pub mod allocator;
 pub mod allocation;
+pub mod bitmap;
 pub mod layer;
+pub mod shelf;
 
 use layer::Layer;
-use wgpu::{SurfaceConfiguration, TextureFormat};
+use wgpu::{util::DeviceExt, TextureFormat};
+
+use crate::LoadingError;
 
 use self::{allocation::Allocation, allocator::Allocator};
 
@@ -11,32 +15,85 @@ pub struct Atlas {
     texture: wgpu::Texture,
     texture_view: wgpu::TextureView,
     layers: Vec<Layer>,
+    budget: Option<AtlasBudget>,
+    width: u32,
+    max_layers: u32,
+    gl: bool,
     pub texture_format: wgpu::TextureFormat,
 }
 
-pub const SIZE: u32 = 2048;
+/// Dimensions of the atlas textures. Layers are square and at least `MIN_WIDTH` texels
+/// wide, the texture starts with `initial_layers` and grows one layer at a time up to
+/// `max_layers`.
+///
+/// `backend` is the backend of the adapter the device was requested from. The GL backend
+/// needs a spare layer for some layer counts, it is kept when the backend isn't known.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AtlasConfig {
+    pub width: u32,
+    pub initial_layers: u32,
+    pub max_layers: u32,
+    pub backend: Option<wgpu::Backend>,
+}
+
+impl Default for AtlasConfig {
+    fn default() -> Self {
+        Self {
+            width: 2048,
+            initial_layers: 2,
+            max_layers: 256,
+            backend: None,
+        }
+    }
+}
+
+impl AtlasConfig {
+    /// Glyphs split in every band have at least 61 curves and take more than 600 texels,
+    /// narrower layers couldn't hold them.
+    pub const MIN_WIDTH: u32 = 32;
+
+    /// Clamp the dimensions to what the device supports, keeping room for the spare layer
+    /// some layer counts need on the GL backend. Layers are widened to `MIN_WIDTH`.
+    pub fn fit(self, limits: &wgpu::Limits) -> Self {
+        let limit = limits.max_texture_array_layers;
+        let limit = if self.is_gl() && limit % 6 == 0 { limit - 1 } else { limit };
+        let max_layers = self.max_layers.clamp(1, limit);
+
+        Self {
+            width: self.width.max(Self::MIN_WIDTH).min(limits.max_texture_dimension_2d),
+            initial_layers: self.initial_layers.clamp(1, max_layers),
+            max_layers,
+            backend: self.backend,
+        }
+    }
+
+    fn is_gl(&self) -> bool {
+        matches!(self.backend, None | Some(wgpu::Backend::Gl))
+    }
+}
+
+/// Upper bound on the size of the atlas texture. Once reached, uploads fail
+/// instead of adding layers to the texture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtlasBudget {
+    Layers(usize),
+    Bytes(u64),
+}
+
+impl AtlasBudget {
+    /// Layers allowed by the budget, for layers of `layer_bytes` bytes.
+    pub fn max_layers(&self, layer_bytes: u64) -> usize {
+        match *self {
+            AtlasBudget::Layers(layers) => layers.max(1),
+            AtlasBudget::Bytes(bytes) => (bytes / layer_bytes).max(1) as usize,
+        }
+    }
+}
 
 impl Atlas {
-    pub fn new(device: &wgpu::Device, surface_config: &SurfaceConfiguration) -> Self {
-        
-        let extent = wgpu::Extent3d {
-            width: SIZE,
-            height: SIZE,
-            depth_or_array_layers: 2,
-        };
-
-        let texture = device.create_texture(&wgpu::TextureDescriptor {
-            label: Some("Atlas Texture"),
-            size: extent,
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: wgpu::TextureDimension::D2,
-            format: TextureFormat::R32Float,
-            usage: wgpu::TextureUsages::COPY_DST
-                 | wgpu::TextureUsages::COPY_SRC
-                 | wgpu::TextureUsages::TEXTURE_BINDING,
-            view_formats: &[TextureFormat::R32Float],
-        });
+    /// `config` is expected to fit the limits of `device`, see `AtlasConfig::fit`.
+    pub fn new(device: &wgpu::Device, config: AtlasConfig) -> Self {
+        let texture = create_texture(device, config.width, texture_layers(config.initial_layers, config.is_gl()));
 
         let texture_view = texture.create_view(&wgpu::TextureViewDescriptor {
             dimension: Some(wgpu::TextureViewDimension::D2Array),
@@ -46,7 +103,11 @@ impl Atlas {
         Self {
             texture,
             texture_view,
-            layers: vec![Layer::Empty],
+            layers: (0..config.initial_layers.min(config.max_layers)).map(|_| Layer::Empty).collect(),
+            budget: None,
+            width: config.width,
+            max_layers: config.max_layers,
+            gl: config.is_gl(),
             texture_format: TextureFormat::R32Float,
         }
     }
@@ -59,44 +120,105 @@ impl Atlas {
         self.layers.len()
     }
 
-    fn allocate(&mut self, width: u32) -> Option<Allocation> {
+    /// Width and height of the layers, in texels
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn budget(&self) -> Option<AtlasBudget> {
+        self.budget
+    }
+
+    pub fn set_budget(&mut self, budget: Option<AtlasBudget>) {
+        self.budget = budget;
+    }
+
+    /// Give the space of an allocation back to its layer. A layer without any
+    /// allocation left goes back to being empty.
+    pub fn deallocate(&mut self, allocation: Allocation) {
+        let layer = &mut self.layers[allocation.layer()];
+
+        if let Layer::Busy(allocator) = layer {
+            allocator.deallocate(&allocation.region);
+
+            if allocator.is_empty() {
+                *layer = Layer::Empty;
+            }
+        }
+    }
+
+    /// Share of the free space that can't be used by a single allocation, from 0 (one
+    /// contiguous span per layer) to 1.
+    pub fn fragmentation(&self) -> f32 {
+        let layer_size = self.width * self.width;
+
+        let (free, largest) = self.layers.iter().fold((0u64, 0u64), |(free, largest), layer| match layer {
+            Layer::Empty => (free + layer_size as u64, largest + layer_size as u64),
+            Layer::Busy(allocator) => (
+                free + allocator.free_space() as u64,
+                largest + allocator.largest_free_span() as u64,
+            ),
+        });
+
+        if free == 0 {
+            0.
+        } else {
+            1. - largest as f32 / free as f32
+        }
+    }
+
+    fn allocate(&mut self, width: u32) -> Result<Allocation, LoadingError> {
+        let (layer, region) = self.allocate_with(|allocator| allocator.allocate(width))?;
+
+        Ok(Allocation {
+            region,
+            layer,
+        })
+    }
+
+    // Run `allocate` on every layer until one has room, adding a layer if none does
+    fn allocate_with<T>(
+        &mut self,
+        mut allocate: impl FnMut(&mut Allocator) -> Option<T>
+    ) -> Result<(usize, T), LoadingError> {
         for (i, layer) in self.layers.iter_mut().enumerate() {
             match layer {
                 Layer::Empty => {
-                    let mut allocator = Allocator::new(SIZE);
+                    let mut allocator = Allocator::new(self.width);
 
-                    if let Some(region) = allocator.allocate(width) {
+                    if let Some(allocated) = allocate(&mut allocator) {
                         *layer = Layer::Busy(allocator);
 
-                        return Some(Allocation {
-                            region,
-                            layer: i,
-                        });
+                        return Ok((i, allocated));
                     }
                 }
                 Layer::Busy(allocator) => {
-                    if let Some(region) = allocator.allocate(width) {
-                        return Some(Allocation {
-                            region,
-                            layer: i,
-                        })
+                    if let Some(allocated) = allocate(allocator) {
+                        return Ok((i, allocated));
                     }
                 }
             }
         }
 
-        let mut allocator = Allocator::new(SIZE);
+        let layer_bytes = self.width as u64 * self.width as u64 * 4;
 
-        if let Some(region) = allocator.allocate(width) {
-            self.layers.push(Layer::Busy(allocator));
+        if self.budget.is_some_and(|budget| self.layers.len() >= budget.max_layers(layer_bytes)) {
+            return Err(LoadingError::AtlasExhausted);
+        }
+
+        let layers = self.layers.len() as u32 + 1;
 
-            return Some(Allocation {
-                region,
-                layer: self.layers.len() - 1,
-            });
+        if layers > self.max_layers {
+            return Err(LoadingError::TextureLimitExceeded { layers, limit: self.max_layers });
         }
 
-        None
+        let mut allocator = Allocator::new(self.width);
+
+        let allocated = allocate(&mut allocator).ok_or(LoadingError::GlyphTooLarge)?;
+
+        self.layers.push(Layer::Busy(allocator));
+
+        Ok((self.layers.len() - 1, allocated))
     }
 
     pub fn upload(
@@ -105,116 +227,159 @@ impl Atlas {
         data: &[u8],
         device: &wgpu::Device,
         encoder: &mut wgpu::CommandEncoder,
-        queue: &wgpu::Queue,
-    ) -> Option<Allocation> {
-        // use wgpu::util::DeviceExt;
-
-        let current_size = self.layers.len();
+    ) -> Result<Allocation, LoadingError> {
         let allocation = self.allocate(size)?;
 
-        let new_layers = self.layers.len() - current_size;
+        self.grow(device, encoder);
 
-        self.grow(new_layers, device, encoder);
+        self.upload_allocation(data, allocation.position(), allocation.size(), allocation.layer(), device, encoder);
 
-        self.upload_allocation(&data, &allocation, queue);
-
-        Some(allocation)
+        Ok(allocation)
     }
 
-    fn upload_allocation(
+    /// Store the curves of several glyphs, `data` holding them back to back and `sizes`
+    /// giving the number of texels of each glyph. They are packed in a single span when a
+    /// layer has room for it, so that they are written with a few texture copies.
+    pub fn upload_many(
         &mut self,
+        sizes: &[u32],
         data: &[u8],
-        allocation: &Allocation,
-        queue: &wgpu::Queue,
-    ) {
-        let [x, y] = allocation.position();
-        let size = allocation.size();
-        let layer = allocation.layer();
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+    ) -> Result<Vec<Allocation>, LoadingError> {
+        if sizes.is_empty() {
+            return Ok(Vec::new());
+        }
 
-        let mut blocks: Vec<[u32; 5]> = Vec::new();
+        if let Ok((layer, regions)) = self.allocate_with(|allocator| allocator.allocate_many(sizes)) {
+            self.grow(device, encoder);
 
-        let first_line = SIZE - x;
+            self.upload_allocation(data, regions[0].position(), sizes.iter().sum(), layer, device, encoder);
 
-        if size < first_line {
-            blocks.push([x, y, size, size, 1]);
-        } else {
-            let nb_lines = f32::ceil((size as f32 - first_line as f32) / SIZE as f32);
+            return Ok(regions.into_iter().map(|region| Allocation { region, layer }).collect());
+        }
 
-            let last_line = (size as f32 - first_line as f32) % SIZE as f32;
+        // No layer has a span large enough, upload the glyphs one by one
+        let mut allocations = Vec::new();
+        let mut offset = 0;
 
-            blocks.push([x, y, first_line, first_line, 1]);
+        for size in sizes {
+            let bytes = *size as usize * 4;
 
-            if nb_lines > 1. {
-                blocks.push([0, y + 1, size - first_line - last_line as u32, SIZE, nb_lines as u32]);
-            }
+            match self.upload(*size, &data[offset..offset + bytes], device, encoder) {
+                Ok(allocation) => allocations.push(allocation),
+                Err(error) => {
+                    for allocation in allocations {
+                        self.deallocate(allocation);
+                    }
 
-            if last_line != 0. {
-                blocks.push([0, y + f32::max(nb_lines, 1.) as u32, last_line as u32, last_line as u32, 1]);
+                    return Err(error);
+                }
             }
+
+            offset += bytes;
         }
 
-        let mut offset = 0;
+        Ok(allocations)
+    }
 
-        blocks.iter().for_each(|[x, y, size, width, height]| {
-            let extent = wgpu::Extent3d {
-                width: *width,
-                height: *height,
-                depth_or_array_layers: 1,
-            };
+    // Write `size` texels starting at `position`, wrapping over as many rows as needed. The
+    // copies are recorded in `encoder` like the ones of `grow`, so that they run in order.
+    // The span is written with at most three copies: the end of its first row, its full
+    // rows at once and the start of its last row.
+    fn upload_allocation(
+        &mut self,
+        data: &[u8],
+        [x, y]: [u32; 2],
+        size: u32,
+        layer: usize,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+    ) {
+        let data = &data[..size as usize * 4];
 
-            let byte_size = *size as usize * 4;
+        let head = if x == 0 { 0 } else { size.min(self.width - x) };
+        let rows = (size - head) / self.width;
+        let tail = (size - head) % self.width;
 
-            let data_slice = &data[offset..offset + byte_size];
+        // Buffer rows of a copy have to be aligned, the full rows are padded
+        let row_bytes = self.width as usize * 4;
+        let padded_row_bytes = row_bytes.next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT as usize);
+        let (head_bytes, rest) = data.split_at(head as usize * 4);
+        let (rows_bytes, tail_bytes) = rest.split_at(rows as usize * row_bytes);
 
-            queue.write_texture(wgpu::ImageCopyTexture {
-                texture: &self.texture,
-                mip_level: 0,
-                origin: wgpu::Origin3d {
-                    x: *x,
-                    y: *y,
-                    z: layer as u32,
-                },
-                aspect: wgpu::TextureAspect::default()
-            }, data_slice, wgpu::ImageDataLayout {
-                offset: 0,
-                bytes_per_row: Some(width * 4),
-                rows_per_image: Some(*height),
-            }, extent);
-
-            offset += byte_size;
+        let mut contents = Vec::with_capacity(head_bytes.len() + rows as usize * padded_row_bytes + tail_bytes.len());
+        contents.extend_from_slice(head_bytes);
+
+        for row in rows_bytes.chunks_exact(row_bytes) {
+            contents.extend_from_slice(row);
+            contents.resize(contents.len() + padded_row_bytes - row_bytes, 0);
+        }
+
+        contents.extend_from_slice(tail_bytes);
+
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Atlas upload buffer"),
+            contents: &contents,
+            usage: wgpu::BufferUsages::COPY_SRC,
         });
+
+        let first_row = if head == 0 { y } else { y + 1 };
+
+        let copies = [
+            (0, [x, y], [head, 1]),
+            (head_bytes.len(), [0, first_row], [self.width, rows]),
+            (head_bytes.len() + rows as usize * padded_row_bytes, [0, first_row + rows], [tail, 1]),
+        ];
+
+        for (offset, [x, y], [width, height]) in copies {
+            if width == 0 || height == 0 {
+                continue;
+            }
+
+            encoder.copy_buffer_to_texture(
+                wgpu::ImageCopyBuffer {
+                    buffer: &buffer,
+                    layout: wgpu::ImageDataLayout {
+                        offset: offset as u64,
+                        bytes_per_row: Some(padded_row_bytes as u32),
+                        rows_per_image: None,
+                    },
+                },
+                wgpu::ImageCopyTexture {
+                    texture: &self.texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d {
+                        x,
+                        y,
+                        z: layer as u32,
+                    },
+                    aspect: wgpu::TextureAspect::default()
+                },
+                wgpu::Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
     }
 
+    // Recreate the texture with room for every layer and copy the layers in use into it
     fn grow(
         &mut self,
-        amount: usize,
         device: &wgpu::Device,
         encoder: &mut wgpu::CommandEncoder,
     ) {
-        if amount == 0 {
+        let current_layers = self.texture.depth_or_array_layers() as usize;
+
+        if self.layers.len() <= current_layers {
             return;
         }
 
-        let new_texture = device.create_texture(&wgpu::TextureDescriptor {
-            label: Some("Atlas Texture"),
-            size: wgpu::Extent3d {
-                width: SIZE,
-                height: SIZE,
-                depth_or_array_layers: self.layers.len() as u32,
-            },
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: wgpu::TextureDimension::D2,
-            format: TextureFormat::R32Float,
-            usage: wgpu::TextureUsages::COPY_DST
-                 | wgpu::TextureUsages::COPY_SRC
-                 | wgpu::TextureUsages::TEXTURE_BINDING,
-            view_formats: &[TextureFormat::R32Float],
-        });
-
-        let layers_to_copy = self.layers.len() - amount;
+        let new_texture = create_texture(device, self.width, texture_layers(self.layers.len() as u32, self.gl));
 
-        for (i, layer) in self.layers.iter_mut().take(layers_to_copy).enumerate() {
+        for (i, layer) in self.layers.iter().take(current_layers).enumerate() {
             if layer.is_empty() {
                 continue;
             }
@@ -241,8 +406,8 @@ impl Atlas {
                     aspect: wgpu::TextureAspect::default()
                 },
                 wgpu::Extent3d {
-                    width: SIZE,
-                    height: SIZE,
+                    width: self.width,
+                    height: self.width,
                     depth_or_array_layers: 1,
                 }
             )
@@ -254,4 +419,77 @@ impl Atlas {
             ..Default::default()
         });
     }
-}
\ No newline at end of file
+}
+
+// Layers given to a texture holding `layers` layers. The GL backend guesses the kind of
+// texture from its size, single layer textures become plain 2D textures and square ones
+// with a multiple of 6 layers become cube maps, neither can be bound as an array.
+fn texture_layers(layers: u32, gl: bool) -> u32 {
+    if !gl {
+        return layers;
+    }
+
+    let layers = layers.max(2);
+
+    if layers % 6 == 0 {
+        layers + 1
+    } else {
+        layers
+    }
+}
+
+fn create_texture(device: &wgpu::Device, width: u32, layers: u32) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Atlas Texture"),
+        size: wgpu::Extent3d {
+            width,
+            height: width,
+            depth_or_array_layers: layers,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: TextureFormat::R32Float,
+        usage: wgpu::TextureUsages::COPY_DST
+             | wgpu::TextureUsages::COPY_SRC
+             | wgpu::TextureUsages::TEXTURE_BINDING,
+        view_formats: &[TextureFormat::R32Float],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spare_layers_are_only_added_for_gl() {
+        assert_eq!(texture_layers(1, true), 2);
+        assert_eq!(texture_layers(6, true), 7);
+        assert_eq!(texture_layers(7, true), 7);
+        assert_eq!(texture_layers(1, false), 1);
+        assert_eq!(texture_layers(6, false), 6);
+    }
+
+    #[test]
+    fn fit_keeps_the_initial_layers() {
+        let limits = wgpu::Limits { max_texture_array_layers: 12, ..wgpu::Limits::downlevel_defaults() };
+        let config = AtlasConfig { width: 1024, initial_layers: 1, max_layers: 64, backend: Some(wgpu::Backend::Vulkan) };
+
+        assert_eq!(config.fit(&limits), AtlasConfig { max_layers: 12, ..config });
+
+        // Room is kept for the spare layer
+        let config = AtlasConfig { backend: Some(wgpu::Backend::Gl), ..config };
+        assert_eq!(config.fit(&limits), AtlasConfig { max_layers: 11, ..config });
+        assert_eq!(AtlasConfig { backend: None, ..config }.fit(&limits).max_layers, 11);
+    }
+
+    #[test]
+    fn fit_clamps_the_width() {
+        let limits = wgpu::Limits::downlevel_defaults();
+        let config = |width| AtlasConfig { width, ..AtlasConfig::default() }.fit(&limits).width;
+
+        assert_eq!(config(1), AtlasConfig::MIN_WIDTH);
+        assert_eq!(config(100), 100);
+        assert_eq!(config(u32::MAX), limits.max_texture_dimension_2d);
+    }
+}
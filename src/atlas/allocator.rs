@@ -1,10 +1,25 @@
+/// Allocates spans of texels inside a square layer. The layer is addressed as a
+/// single line of `size * size` texels, an allocation can wrap over several rows.
 #[derive(Debug)]
 pub struct Allocator {
-    offset: u32,
     size: u32,
+    free: FreeSpans,
     allocations: usize,
 }
 
+/// Free parts of a line of texels, allocated best fit first.
+#[derive(Debug)]
+pub struct FreeSpans {
+    // Sorted by offset, adjacent spans are always merged
+    spans: Vec<Span>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Span {
+    offset: u32,
+    size: u32,
+}
+
 #[derive(Debug)]
 pub struct Region {
     position: [u32; 2],
@@ -21,33 +36,202 @@ impl Region {
     }
 }
 
+impl FreeSpans {
+    pub fn new(length: u32) -> FreeSpans {
+        FreeSpans {
+            spans: vec![Span { offset: 0, size: length }],
+        }
+    }
+
+    /// Take `size` texels from the smallest span holding them and return their offset.
+    pub fn allocate(&mut self, size: u32) -> Option<u32> {
+        if size == 0 {
+            return None;
+        }
+
+        // Best fit keeps large spans available for large glyphs
+        let (index, span) = self.spans.iter()
+            .copied()
+            .enumerate()
+            .filter(|(_, span)| span.size >= size)
+            .min_by_key(|(_, span)| span.size)?;
+
+        if span.size == size {
+            self.spans.remove(index);
+        } else {
+            self.spans[index] = Span {
+                offset: span.offset + size,
+                size: span.size - size,
+            };
+        }
+
+        Some(span.offset)
+    }
+
+    pub fn deallocate(&mut self, offset: u32, size: u32) {
+        let index = self.spans.partition_point(|span| span.offset < offset);
+
+        self.spans.insert(index, Span { offset, size });
+
+        // Merge with the following span, then with the preceding one
+        if index + 1 < self.spans.len() && offset + size == self.spans[index + 1].offset {
+            self.spans[index].size += self.spans[index + 1].size;
+            self.spans.remove(index + 1);
+        }
+
+        if index > 0 && self.spans[index - 1].offset + self.spans[index - 1].size == offset {
+            self.spans[index - 1].size += self.spans[index].size;
+            self.spans.remove(index);
+        }
+    }
+
+    pub fn free_space(&self) -> u32 {
+        self.spans.iter().map(|span| span.size).sum()
+    }
+
+    pub fn largest(&self) -> u32 {
+        self.spans.iter().map(|span| span.size).max().unwrap_or(0)
+    }
+}
+
 impl Allocator {
     pub fn new(size: u32) -> Allocator {
         Allocator {
-            offset: 0,
             size,
+            free: FreeSpans::new(size * size),
             allocations: 0,
         }
     }
 
     pub fn allocate(&mut self, size: u32) -> Option<Region> {
-        let x =self.offset as f32 % self.size as f32;
-        let row_index = f32::floor(self.offset as f32 / self.size as f32);
-        let total_size = (self.size * self.size) as f32;
-        let size_left = total_size - self.offset as f32;
-        self.offset += size;
-        if size as f32 > size_left {
-            None
-        } else {
-            self.allocations += 1;
-            Some(Region {
-                position: [x as u32, row_index as u32],
-                size,
-            })
+        let offset = self.free.allocate(size)?;
+
+        self.allocations += 1;
+
+        Some(Region {
+            position: [offset % self.size, offset / self.size],
+            size,
+        })
+    }
+
+    /// Allocate regions of `sizes` back to back in a single span, so that their content
+    /// can be written at once. Every region is deallocated on its own.
+    pub fn allocate_many(&mut self, sizes: &[u32]) -> Option<Vec<Region>> {
+        if sizes.is_empty() || sizes.contains(&0) {
+            return None;
         }
+
+        let mut offset = self.free.allocate(sizes.iter().sum())?;
+
+        self.allocations += sizes.len();
+
+        Some(sizes.iter().map(|size| {
+            let region = Region {
+                position: [offset % self.size, offset / self.size],
+                size: *size,
+            };
+
+            offset += size;
+
+            region
+        }).collect())
+    }
+
+    pub fn deallocate(&mut self, region: &Region) {
+        let [x, y] = region.position;
+
+        self.free.deallocate(y * self.size + x, region.size);
+
+        self.allocations -= 1;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allocations == 0
     }
 
-    // pub fn is_empty(&self) -> bool {
-    //     self.allocations == 0
-    // }
-}
\ No newline at end of file
+    pub fn free_space(&self) -> u32 {
+        self.free.free_space()
+    }
+
+    pub fn largest_free_span(&self) -> u32 {
+        self.free.largest()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocations_wrap_over_rows() {
+        let mut allocator = Allocator::new(4);
+
+        let first = allocator.allocate(3).unwrap();
+        let second = allocator.allocate(6).unwrap();
+
+        assert_eq!(first.position(), [0, 0]);
+        assert_eq!(second.position(), [3, 0]);
+        assert_eq!(allocator.free_space(), 7);
+        assert!(allocator.allocate(8).is_none());
+        assert!(allocator.allocate(0).is_none());
+    }
+
+    #[test]
+    fn freed_spans_merge_on_both_sides() {
+        let mut allocator = Allocator::new(4);
+
+        let regions: Vec<Region> = (0..4).map(|_| allocator.allocate(4).unwrap()).collect();
+
+        allocator.deallocate(&regions[0]);
+        allocator.deallocate(&regions[2]);
+        assert_eq!(allocator.largest_free_span(), 4);
+
+        // Fills the gap between the two free spans
+        allocator.deallocate(&regions[1]);
+        assert_eq!(allocator.largest_free_span(), 12);
+        assert_eq!(allocator.free.spans.len(), 1);
+
+        allocator.deallocate(&regions[3]);
+        assert!(allocator.is_empty());
+        assert_eq!(allocator.largest_free_span(), 16);
+    }
+
+    #[test]
+    fn freed_spans_are_reused_best_fit_first() {
+        let mut allocator = Allocator::new(4);
+
+        let small = allocator.allocate(2).unwrap();
+        let _separator = allocator.allocate(1).unwrap();
+        let large = allocator.allocate(5).unwrap();
+        let _rest = allocator.allocate(8).unwrap();
+
+        allocator.deallocate(&large);
+        allocator.deallocate(&small);
+
+        // The span of 2 texels fits exactly, the span of 5 stays whole
+        assert_eq!(allocator.allocate(2).unwrap().position(), [0, 0]);
+        assert_eq!(allocator.largest_free_span(), 5);
+        assert_eq!(allocator.allocate(5).unwrap().position(), [3, 0]);
+        assert_eq!(allocator.largest_free_span(), 0);
+    }
+
+    #[test]
+    fn regions_allocated_together_are_freed_one_by_one() {
+        let mut allocator = Allocator::new(4);
+
+        let regions = allocator.allocate_many(&[3, 2, 5]).unwrap();
+        let positions: Vec<[u32; 2]> = regions.iter().map(Region::position).collect();
+
+        assert_eq!(positions, vec![[0, 0], [3, 0], [1, 1]]);
+        assert!(allocator.allocate_many(&[2, 0]).is_none());
+        assert!(allocator.allocate_many(&[4, 3]).is_none());
+
+        allocator.deallocate(&regions[1]);
+        allocator.deallocate(&regions[0]);
+        assert!(!allocator.is_empty());
+        assert_eq!(allocator.largest_free_span(), 6);
+
+        allocator.deallocate(&regions[2]);
+        assert!(allocator.is_empty());
+    }
+}
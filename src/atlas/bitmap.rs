@@ -0,0 +1,237 @@
+use wgpu::{util::DeviceExt, TextureFormat};
+
+use crate::LoadingError;
+
+use super::{shelf::{Rectangle, ShelfAllocator}, texture_layers, AtlasBudget, AtlasConfig};
+
+// Transparent border around every image, so that filtering doesn't bleed between neighbours
+const PADDING: u32 = 1;
+
+/// Texture atlas holding the images of bitmap glyphs, in sRGB encoded RGBA premultiplied
+/// in linear space.
+pub struct BitmapAtlas {
+    texture: wgpu::Texture,
+    texture_view: wgpu::TextureView,
+    layers: Vec<ShelfAllocator>,
+    budget: Option<AtlasBudget>,
+    width: u32,
+    max_layers: u32,
+    gl: bool,
+}
+
+#[derive(Debug)]
+pub struct BitmapAllocation {
+    pub layer: usize,
+    pub rectangle: Rectangle,
+}
+
+impl BitmapAllocation {
+    /// Top left corner of the image, without the padding
+    pub fn position(&self) -> [u32; 2] {
+        let [x, y] = self.rectangle.position();
+
+        [x + PADDING, y + PADDING]
+    }
+
+    pub fn size(&self) -> [u32; 2] {
+        let [width, height] = self.rectangle.size();
+
+        [width - PADDING * 2, height - PADDING * 2]
+    }
+
+    pub fn layer(&self) -> usize {
+        self.layer
+    }
+}
+
+impl BitmapAtlas {
+    /// `config` is expected to fit the limits of `device`, see `AtlasConfig::fit`.
+    pub fn new(device: &wgpu::Device, config: AtlasConfig) -> Self {
+        let texture = create_texture(device, config.width, texture_layers(config.initial_layers, config.is_gl()));
+
+        let texture_view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+
+        Self {
+            texture,
+            texture_view,
+            layers: Vec::new(),
+            budget: None,
+            width: config.width,
+            max_layers: config.max_layers,
+            gl: config.is_gl(),
+        }
+    }
+
+    pub fn view(&self) -> &wgpu::TextureView {
+        &self.texture_view
+    }
+
+    pub fn layer_count(&self) -> usize {
+        self.layers.len()
+    }
+
+    pub fn set_budget(&mut self, budget: Option<AtlasBudget>) {
+        self.budget = budget;
+    }
+
+    pub fn deallocate(&mut self, allocation: BitmapAllocation) {
+        if let Some(allocator) = self.layers.get_mut(allocation.layer) {
+            allocator.deallocate(&allocation.rectangle);
+        }
+    }
+
+    fn allocate(&mut self, width: u32, height: u32) -> Result<BitmapAllocation, LoadingError> {
+        let (width, height) = (width + PADDING * 2, height + PADDING * 2);
+
+        for (i, allocator) in self.layers.iter_mut().enumerate() {
+            if let Some(rectangle) = allocator.allocate(width, height) {
+                return Ok(BitmapAllocation {
+                    layer: i,
+                    rectangle,
+                });
+            }
+        }
+
+        let layer_bytes = self.width as u64 * self.width as u64 * 4;
+
+        if self.budget.is_some_and(|budget| self.layers.len() >= budget.max_layers(layer_bytes)) {
+            return Err(LoadingError::AtlasExhausted);
+        }
+
+        let layers = self.layers.len() as u32 + 1;
+
+        if layers > self.max_layers {
+            return Err(LoadingError::TextureLimitExceeded { layers, limit: self.max_layers });
+        }
+
+        let mut allocator = ShelfAllocator::new(self.width);
+
+        let rectangle = allocator.allocate(width, height).ok_or(LoadingError::GlyphTooLarge)?;
+
+        self.layers.push(allocator);
+
+        Ok(BitmapAllocation {
+            layer: self.layers.len() - 1,
+            rectangle,
+        })
+    }
+
+    /// Store an RGBA image of `width` by `height` pixels. Fails if the image doesn't fit
+    /// in a layer, the budget of the atlas is reached or the device can't add a layer.
+    pub fn upload(
+        &mut self,
+        width: u32,
+        height: u32,
+        data: &[u8],
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+    ) -> Result<BitmapAllocation, LoadingError> {
+        let current_size = self.layers.len();
+        let allocation = self.allocate(width, height)?;
+
+        if self.layers.len() > current_size {
+            self.grow(current_size, device, encoder);
+        }
+
+        let [padded_width, padded_height] = allocation.rectangle.size();
+
+        // Rows of buffer copies have to be aligned, the padding texels are cleared as well
+        let bytes_per_row = (padded_width * 4).next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
+        let mut pixels = vec![0u8; (bytes_per_row * padded_height) as usize];
+
+        for (row, line) in data.chunks_exact(width as usize * 4).take(height as usize).enumerate() {
+            let start = (row as u32 + PADDING) * bytes_per_row + PADDING * 4;
+            pixels[start as usize..start as usize + line.len()].copy_from_slice(line);
+        }
+
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Bitmap atlas upload buffer"),
+            contents: &pixels,
+            usage: wgpu::BufferUsages::COPY_SRC,
+        });
+
+        let [x, y] = allocation.rectangle.position();
+
+        encoder.copy_buffer_to_texture(
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(bytes_per_row),
+                    rows_per_image: Some(padded_height),
+                },
+            },
+            wgpu::ImageCopyTexture {
+                texture: &self.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d {
+                    x,
+                    y,
+                    z: allocation.layer as u32,
+                },
+                aspect: wgpu::TextureAspect::default()
+            },
+            wgpu::Extent3d {
+                width: padded_width,
+                height: padded_height,
+                depth_or_array_layers: 1,
+            },
+        );
+
+        Ok(allocation)
+    }
+
+    // Recreate the texture with room for every layer and copy the `used` first layers into it
+    fn grow(
+        &mut self,
+        used: usize,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+    ) {
+        if self.layers.len() <= self.texture.depth_or_array_layers() as usize {
+            return;
+        }
+
+        let new_texture = create_texture(device, self.width, texture_layers(self.layers.len() as u32, self.gl));
+
+        if used > 0 {
+            encoder.copy_texture_to_texture(
+                self.texture.as_image_copy(),
+                new_texture.as_image_copy(),
+                wgpu::Extent3d {
+                    width: self.width,
+                    height: self.width,
+                    depth_or_array_layers: used as u32,
+                }
+            );
+        }
+
+        self.texture = new_texture;
+        self.texture_view = self.texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+    }
+}
+
+fn create_texture(device: &wgpu::Device, width: u32, layers: u32) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Bitmap Atlas Texture"),
+        size: wgpu::Extent3d {
+            width,
+            height: width,
+            depth_or_array_layers: layers,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: TextureFormat::Rgba8UnormSrgb,
+        usage: wgpu::TextureUsages::COPY_DST
+             | wgpu::TextureUsages::COPY_SRC
+             | wgpu::TextureUsages::TEXTURE_BINDING,
+        view_formats: &[TextureFormat::Rgba8UnormSrgb],
+    })
+}
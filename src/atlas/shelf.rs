@@ -0,0 +1,154 @@
+use super::allocator::FreeSpans;
+
+/// Allocates rectangles inside a square layer. Rectangles are packed side by side
+/// in shelves, rows of the layer as tall as the first rectangle placed in them.
+#[derive(Debug)]
+pub struct ShelfAllocator {
+    size: u32,
+    // Shelves sorted from top to bottom, without gaps between them
+    shelves: Vec<Shelf>,
+}
+
+#[derive(Debug)]
+struct Shelf {
+    y: u32,
+    height: u32,
+    // Free horizontal spans of the shelf
+    free: FreeSpans,
+}
+
+#[derive(Debug)]
+pub struct Rectangle {
+    position: [u32; 2],
+    size: [u32; 2],
+}
+
+impl Rectangle {
+    pub fn position(&self) -> [u32; 2] {
+        self.position
+    }
+
+    pub fn size(&self) -> [u32; 2] {
+        self.size
+    }
+}
+
+impl ShelfAllocator {
+    pub fn new(size: u32) -> ShelfAllocator {
+        ShelfAllocator {
+            size,
+            shelves: Vec::new(),
+        }
+    }
+
+    pub fn allocate(&mut self, width: u32, height: u32) -> Option<Rectangle> {
+        if width == 0 || height == 0 || width > self.size || height > self.size {
+            return None;
+        }
+
+        // Shelf wasting the least height
+        let best = self.shelves.iter()
+            .enumerate()
+            .filter(|(_, shelf)| shelf.height >= height && shelf.free.largest() >= width)
+            .map(|(index, shelf)| (index, shelf.height - height))
+            .min_by_key(|(_, waste)| *waste);
+
+        let top = self.shelves.last().map_or(0, |shelf| shelf.y + shelf.height);
+        let fits_new_shelf = top + height <= self.size;
+
+        // Rather open a new shelf than fill a much taller one
+        let index = match best {
+            Some((index, waste)) if waste <= height / 2 || !fits_new_shelf => index,
+            _ if fits_new_shelf => {
+                self.shelves.push(Shelf {
+                    y: top,
+                    height,
+                    free: FreeSpans::new(self.size),
+                });
+
+                self.shelves.len() - 1
+            }
+            _ => return None,
+        };
+
+        let shelf = &mut self.shelves[index];
+        let x = shelf.free.allocate(width)?;
+
+        Some(Rectangle {
+            position: [x, shelf.y],
+            size: [width, height],
+        })
+    }
+
+    pub fn deallocate(&mut self, rectangle: &Rectangle) {
+        let [x, y] = rectangle.position;
+
+        let Some(shelf) = self.shelves.iter_mut().find(|shelf| shelf.y == y) else {
+            return;
+        };
+
+        shelf.free.deallocate(x, rectangle.size[0]);
+
+        // Empty shelves at the bottom are given back so that they can be reopened with another height
+        while self.shelves.last().is_some_and(|shelf| shelf.free.free_space() == self.size) {
+            self.shelves.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rectangles_share_shelves_of_similar_height() {
+        let mut allocator = ShelfAllocator::new(16);
+
+        let first = allocator.allocate(6, 8).unwrap();
+        let second = allocator.allocate(6, 6).unwrap();
+
+        assert_eq!(first.position(), [0, 0]);
+        assert_eq!(second.position(), [6, 0]);
+
+        // Would waste more than half of the shelf height
+        let short = allocator.allocate(4, 3).unwrap();
+        assert_eq!(short.position(), [0, 8]);
+
+        // Too wide for the rest of the first shelf
+        assert_eq!(allocator.allocate(5, 5).unwrap().position(), [0, 11]);
+        assert!(allocator.allocate(5, 8).is_none());
+        assert!(allocator.allocate(17, 1).is_none());
+        assert!(allocator.allocate(0, 1).is_none());
+    }
+
+    #[test]
+    fn taller_shelves_are_used_when_the_layer_is_full() {
+        let mut allocator = ShelfAllocator::new(16);
+
+        allocator.allocate(4, 16).unwrap();
+
+        assert_eq!(allocator.allocate(4, 2).unwrap().position(), [4, 0]);
+    }
+
+    #[test]
+    fn freed_spans_merge_and_empty_shelves_are_given_back() {
+        let mut allocator = ShelfAllocator::new(16);
+
+        let top = allocator.allocate(16, 4).unwrap();
+        let rectangles: Vec<Rectangle> = (0..3).map(|_| allocator.allocate(4, 8).unwrap()).collect();
+
+        allocator.deallocate(&rectangles[0]);
+        allocator.deallocate(&rectangles[2]);
+
+        // The spans on both sides of the middle rectangle are merged with it
+        allocator.deallocate(&rectangles[1]);
+        assert_eq!(allocator.shelves.len(), 1);
+
+        // The bottom shelf was given back, so a new one can be opened with another height
+        assert_eq!(allocator.allocate(16, 12).unwrap().position(), [0, 4]);
+
+        allocator.deallocate(&top);
+        assert_eq!(allocator.shelves[0].free.largest(), 16);
+        assert_eq!(allocator.allocate(8, 4).unwrap().position(), [0, 0]);
+    }
+}
@@ -0,0 +1,165 @@
+// Glyphs are stored in the atlas with their curves grouped in bands, so that each pixel only
+// visits the curves its rays can cross. Horizontal bands split the glyph along y and hold the
+// curves crossed by horizontal rays, vertical bands split it along x. Layout in texels:
+//
+// header: horizontal band count, vertical band count, band origin x and y, bands per unit along x and y, 0, 0
+// per band, horizontal bands first: offset of its curve list, curve count
+// curve lists: offsets of the curves of each band, by decreasing maximum along the ray
+// curves: x0, y0, control x, control y, x1, y1, 0, 0
+//
+// Offsets are in texels from the start of the glyph.
+const HEADER_SIZE: usize = 8;
+const CURVE_SIZE: usize = 8;
+
+// Bands are added until they hold about this many curves on average
+const CURVES_PER_BAND: usize = 4;
+const MAX_BANDS: usize = 16;
+
+/// Group `curves`, monotonic quadratics back to back, in bands.
+pub(crate) fn build(curves: &[f32]) -> Vec<f32> {
+    let bounds: Vec<[f32; 4]> = curves.chunks_exact(CURVE_SIZE).map(|curve| {
+        let (xs, ys) = ([curve[0], curve[2], curve[4]], [curve[1], curve[3], curve[5]]);
+
+        [
+            xs.into_iter().fold(f32::MAX, f32::min),
+            ys.into_iter().fold(f32::MAX, f32::min),
+            xs.into_iter().fold(f32::MIN, f32::max),
+            ys.into_iter().fold(f32::MIN, f32::max),
+        ]
+    }).collect();
+
+    let [x_min, y_min, x_max, y_max] = bounds.iter().fold([f32::MAX, f32::MAX, f32::MIN, f32::MIN], |total, bounds| {
+        [total[0].min(bounds[0]), total[1].min(bounds[1]), total[2].max(bounds[2]), total[3].max(bounds[3])]
+    });
+
+    let band_count = bounds.len().div_ceil(CURVES_PER_BAND).clamp(1, MAX_BANDS);
+
+    // Bands per unit, a flat glyph gets a single band
+    let scale = |min: f32, max: f32| if max > min { band_count as f32 / (max - min) } else { 0. };
+    let (x_scale, y_scale) = (scale(x_min, x_max), scale(y_min, y_max));
+
+    // Curves that can't cross the rays of a band are left out, flat curves never cross them.
+    // Rays go towards increasing coordinates, the curves are sorted by their furthest point
+    // along the ray so that the shader can stop at the first curve behind the sample.
+    let band_curves = |band: usize, min: f32, scale: f32, axis: usize| -> Vec<usize> {
+        let ray_axis = 1 - axis;
+
+        let mut curves: Vec<usize> = bounds.iter().enumerate().filter(|(_, bounds)| {
+            let (low, high) = (bounds[axis], bounds[axis + 2]);
+
+            if low == high {
+                return false;
+            }
+
+            if scale == 0. {
+                return true;
+            }
+
+            let band_low = min + band as f32 / scale;
+            let band_high = min + (band + 1) as f32 / scale;
+
+            // Bands are widened a little so that samples on their border find every curve
+            let epsilon = (band_high - band_low) * 1e-3;
+
+            high >= band_low - epsilon && low <= band_high + epsilon
+        }).map(|(index, _)| index).collect();
+
+        curves.sort_by(|a, b| bounds[*b][ray_axis + 2].total_cmp(&bounds[*a][ray_axis + 2]));
+        curves
+    };
+
+    let horizontal_bands = (0..band_count).map(|band| band_curves(band, y_min, y_scale, 1));
+    let vertical_bands = (0..band_count).map(|band| band_curves(band, x_min, x_scale, 0));
+    let bands: Vec<Vec<usize>> = horizontal_bands.chain(vertical_bands).collect();
+
+    let lists_start = HEADER_SIZE + bands.len() * 2;
+    let curves_start = lists_start + bands.iter().map(Vec::len).sum::<usize>();
+
+    let mut data = Vec::with_capacity(curves_start + curves.len());
+
+    data.extend_from_slice(&[band_count as f32, band_count as f32, x_min, y_min, x_scale, y_scale, 0., 0.]);
+
+    let mut list_offset = lists_start;
+
+    for band in &bands {
+        data.extend_from_slice(&[list_offset as f32, band.len() as f32]);
+        list_offset += band.len();
+    }
+
+    for band in &bands {
+        data.extend(band.iter().map(|index| (curves_start + index * CURVE_SIZE) as f32));
+    }
+
+    data.extend_from_slice(curves);
+
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A straight line as a quadratic curve
+    fn line(from: [f32; 2], to: [f32; 2]) -> [f32; CURVE_SIZE] {
+        [from[0], from[1], (from[0] + to[0]) / 2., (from[1] + to[1]) / 2., to[0], to[1], 0., 0.]
+    }
+
+    fn square(min: f32, max: f32) -> Vec<f32> {
+        [
+            line([min, min], [max, min]),
+            line([max, min], [max, max]),
+            line([max, max], [min, max]),
+            line([min, max], [min, min]),
+        ].concat()
+    }
+
+    // Indices of the curves listed by band `band`, the curves being the last `curve_count` of `data`
+    fn band_curves(data: &[f32], band: usize, curve_count: usize) -> Vec<usize> {
+        let (offset, count) = (data[HEADER_SIZE + band * 2] as usize, data[HEADER_SIZE + band * 2 + 1] as usize);
+        let curves_start = data.len() - curve_count * CURVE_SIZE;
+
+        data[offset..offset + count].iter().map(|offset| (*offset as usize - curves_start) / CURVE_SIZE).collect()
+    }
+
+    #[test]
+    fn curves_are_grouped_in_bands() {
+        // Two squares in opposite corners, each one only crosses the bands on its side
+        let curves = [square(0., 4.), square(6., 10.)].concat();
+        let data = build(&curves);
+
+        assert_eq!(data[..HEADER_SIZE], [2., 2., 0., 0., 0.2, 0.2, 0., 0.]);
+
+        // Horizontal bands first, flat curves are left out
+        assert_eq!(data[HEADER_SIZE..16], [16., 2., 18., 2., 20., 2., 22., 2.]);
+
+        let mut bands: Vec<Vec<usize>> = (0..4).map(|band| band_curves(&data, band, 8)).collect();
+        bands.iter_mut().for_each(|band| band.sort());
+
+        assert_eq!(bands, vec![vec![1, 3], vec![5, 7], vec![0, 2], vec![4, 6]]);
+        assert_eq!(data[24..], curves[..]);
+    }
+
+    #[test]
+    fn band_curves_are_sorted_along_the_ray() {
+        let curves = [square(0., 4.), square(6., 10.)].concat();
+        let data = build(&curves);
+
+        let bands: Vec<Vec<usize>> = (0..4).map(|band| band_curves(&data, band, 8)).collect();
+
+        // Right edges before left edges, top edges before bottom edges
+        assert_eq!(bands, vec![vec![1, 3], vec![5, 7], vec![2, 0], vec![6, 4]]);
+    }
+
+    #[test]
+    fn flat_glyphs_get_a_single_band() {
+        let curves = line([0., 2.], [8., 2.]);
+        let data = build(&curves);
+
+        assert_eq!(data[..HEADER_SIZE], [1., 1., 0., 2., 0.125, 0., 0., 0.]);
+
+        // The line can't cross horizontal rays
+        assert_eq!(data[HEADER_SIZE..12], [12., 0., 12., 1.]);
+        assert_eq!(data[12], 13.);
+        assert_eq!(data[13..], curves[..]);
+    }
+}
@@ -0,0 +1,183 @@
+use owned_ttf_parser::{Face, GlyphId, RasterGlyphImage, RasterImageFormat};
+
+use crate::{atlas::bitmap::{BitmapAllocation, BitmapAtlas}, color::{linear_to_srgb, srgb_to_linear}, LoadingError};
+
+/// Bitmap glyph stored in the bitmap atlas. Metrics are in pixels of the strike.
+#[derive(Debug)]
+pub struct BitmapGlyph {
+    pub allocation: BitmapAllocation,
+    /// Offset of the bottom left corner of the image from the glyph origin, y going up
+    pub x: i16,
+    pub y: i16,
+    pub width: u32,
+    pub height: u32,
+    pub pixels_per_em: u16,
+    /// Grayscale images are coverage masks drawn with the text color
+    pub mask: bool,
+    /// Frame in which the glyph was last prepared, used to evict glyphs when the atlas is full
+    pub last_used: u64,
+}
+
+impl BitmapGlyph {
+    pub fn new(bitmap: GlyphBitmap, allocation: BitmapAllocation, last_used: u64) -> Self {
+        Self {
+            allocation,
+            x: bitmap.x,
+            y: bitmap.y,
+            width: bitmap.width,
+            height: bitmap.height,
+            pixels_per_em: bitmap.pixels_per_em,
+            mask: bitmap.mask,
+            last_used,
+        }
+    }
+}
+
+/// Glyph image from the `sbix`, `CBDT` or `EBDT` tables decoded to sRGB encoded RGBA,
+/// premultiplied in linear space, before it is uploaded to the bitmap atlas.
+#[derive(Debug)]
+pub struct GlyphBitmap {
+    pub data: Vec<u8>,
+    pub x: i16,
+    pub y: i16,
+    pub width: u32,
+    pub height: u32,
+    pub pixels_per_em: u16,
+    pub mask: bool,
+}
+
+impl GlyphBitmap {
+    // Decode the image of the largest strike, it is scaled down to the font size when drawn
+    pub fn new(face: &Face, glyph_id: GlyphId) -> Option<Self> {
+        let image = face.glyph_raster_image(glyph_id, u16::MAX)?;
+
+        let (width, height, data, mask) = match image.format {
+            RasterImageFormat::PNG => {
+                let (width, height, data) = decode_png(image.data)?;
+                (width, height, data, false)
+            }
+            RasterImageFormat::BitmapPremulBgra32 => {
+                let data = image.data.chunks_exact(4)
+                    .flat_map(|pixel| premultiply(unpremultiply([pixel[2], pixel[1], pixel[0], pixel[3]])))
+                    .collect();
+                (image.width as u32, image.height as u32, data, false)
+            }
+            _ => (image.width as u32, image.height as u32, decode_mask(&image)?, true),
+        };
+
+        if width == 0 || height == 0 || data.len() < (width * height * 4) as usize {
+            return None;
+        }
+
+        Some(Self {
+            data,
+            x: image.x,
+            y: image.y,
+            width,
+            height,
+            pixels_per_em: image.pixels_per_em.max(1),
+            mask,
+        })
+    }
+
+    pub fn upload(
+        &self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        atlas: &mut BitmapAtlas,
+    ) -> Result<BitmapAllocation, LoadingError> {
+        atlas.upload(self.width, self.height, &self.data, device, encoder)
+    }
+}
+
+fn decode_png(data: &[u8]) -> Option<(u32, u32, Vec<u8>)> {
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+
+    let mut reader = decoder.read_info().ok()?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer).ok()?;
+    let pixels = &buffer[..info.buffer_size()];
+
+    let rgba: Vec<u8> = match info.color_type {
+        png::ColorType::Rgba => pixels.to_vec(),
+        png::ColorType::Rgb => pixels.chunks_exact(3).flat_map(|p| [p[0], p[1], p[2], 255]).collect(),
+        png::ColorType::GrayscaleAlpha => pixels.chunks_exact(2).flat_map(|p| [p[0], p[0], p[0], p[1]]).collect(),
+        png::ColorType::Grayscale => pixels.iter().flat_map(|p| [*p, *p, *p, 255]).collect(),
+        png::ColorType::Indexed => return None,
+    };
+
+    let rgba = rgba.chunks_exact(4)
+        .flat_map(|p| premultiply([p[0], p[1], p[2], p[3]]))
+        .collect();
+
+    Some((info.width, info.height, rgba))
+}
+
+// The atlas decodes its sRGB texels to linear values before filtering them, so colors are
+// premultiplied in linear space for the edges to keep their brightness
+fn premultiply([red, green, blue, alpha]: [u8; 4]) -> [u8; 4] {
+    let premultiply = |value: u8| linear_to_srgb(srgb_to_linear(value) * alpha as f32 / 255.);
+
+    [premultiply(red), premultiply(green), premultiply(blue), alpha]
+}
+
+// Images premultiplied in sRGB space, as `CBDT` stores them
+fn unpremultiply([red, green, blue, alpha]: [u8; 4]) -> [u8; 4] {
+    if alpha == 0 {
+        return [0; 4];
+    }
+
+    let unpremultiply = |value: u8| ((value as u32 * 255 + alpha as u32 / 2) / alpha as u32).min(255) as u8;
+
+    [unpremultiply(red), unpremultiply(green), unpremultiply(blue), alpha]
+}
+
+// Expand monochrome and grayscale bitmaps, where set bits are ink, to white coverage masks
+fn decode_mask(image: &RasterGlyphImage) -> Option<Vec<u8>> {
+    let (bits, packed) = match image.format {
+        RasterImageFormat::BitmapMono => (1, false),
+        RasterImageFormat::BitmapMonoPacked => (1, true),
+        RasterImageFormat::BitmapGray2 => (2, false),
+        RasterImageFormat::BitmapGray2Packed => (2, true),
+        RasterImageFormat::BitmapGray4 => (4, false),
+        RasterImageFormat::BitmapGray4Packed => (4, true),
+        RasterImageFormat::BitmapGray8 => (8, false),
+        _ => return None,
+    };
+
+    let (width, height) = (image.width as usize, image.height as usize);
+    let row_bits = if packed { width * bits } else { (width * bits).next_multiple_of(8) };
+    let max = (1u32 << bits) - 1;
+
+    let mut data = Vec::with_capacity(width * height * 4);
+
+    for y in 0..height {
+        for x in 0..width {
+            let bit = y * row_bits + x * bits;
+            let byte = *image.data.get(bit / 8)?;
+            let value = (byte >> (8 - bits - bit % 8)) as u32 & max;
+            let coverage = (value * 255 / max) as u8;
+
+            data.extend_from_slice(&premultiply([255, 255, 255, coverage]));
+        }
+    }
+
+    Some(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colors_are_premultiplied_in_linear_space() {
+        // Half of linear white is brighter than half of its sRGB encoding
+        assert_eq!(premultiply([255, 255, 255, 128]), [188, 188, 188, 128]);
+        assert_eq!(premultiply([255, 0, 255, 255]), [255, 0, 255, 255]);
+        assert_eq!(premultiply([255, 255, 255, 0]), [0; 4]);
+
+        assert_eq!(unpremultiply([64, 128, 0, 128]), [128, 255, 0, 128]);
+        assert_eq!(unpremultiply([12, 34, 56, 0]), [0; 4]);
+    }
+}
@@ -0,0 +1,55 @@
+struct Params {
+    screen_resolution: vec2<f32>,
+    atlas_width: u32,
+    _pad: u32,
+    transform: mat4x4<f32>,
+}
+
+struct VertexInput {
+    @location(0) v_pos: vec2<f32>,
+    @location(1) pos: vec2<f32>,
+    @location(2) size: vec2<f32>,
+    @location(3) atlas_rect: vec4<f32>,
+    @location(4) layer: u32,
+    @location(5) color: vec4<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+    @location(1) @interpolate(flat) layer: i32,
+    @location(2) color: vec4<f32>,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+@group(1) @binding(0) var bitmap_texture: texture_2d_array<f32>;
+@group(1) @binding(1) var bitmap_sampler: sampler;
+
+@vertex
+fn vs_main(input: VertexInput) -> VertexOutput {
+    var output: VertexOutput;
+
+    var transform = mat4x4<f32>(
+        vec4<f32>(input.size.x, 0.,           0., 0.),
+        vec4<f32>(0.,           input.size.y, 0., 0.),
+        vec4<f32>(0.,           0.,           1., 0.),
+        vec4<f32>(input.pos,                  0., 1.),
+    );
+
+    output.position = params.transform * transform * vec4<f32>(input.v_pos, 0., 1.);
+
+    let atlas_size = vec2<f32>(textureDimensions(bitmap_texture));
+    output.uv = (input.atlas_rect.xy + input.v_pos * input.atlas_rect.zw) / atlas_size;
+    output.layer = i32(input.layer);
+    output.color = input.color;
+
+    return output;
+}
+
+@fragment
+fn fs_main(input: VertexOutput) -> @location(0) vec4<f32> {
+    // Texels are premultiplied, masks are white and take the text color
+    let texel = textureSample(bitmap_texture, bitmap_sampler, input.uv, input.layer);
+
+    return vec4<f32>(texel.rgb * input.color.rgb, texel.a) * input.color.a;
+}
@@ -0,0 +1,345 @@
+use owned_ttf_parser::{colr::{ClipBox, ColorStop, CompositeMode, GradientExtend, Paint, Painter}, Face, GlyphId, RectF, RgbaColor, Transform};
+
+/// One layer of a color glyph, an outline filled with a paint.
+#[derive(Debug, Clone)]
+pub struct ColorLayer {
+    pub glyph_id: GlyphId,
+    /// Transform applied to the outline, in font units
+    pub transform: Transform,
+    pub paint: LayerPaint,
+    /// Area outside of which the layer is not drawn, in font units
+    pub clip_box: Option<RectF>,
+}
+
+#[derive(Debug, Clone)]
+pub enum LayerPaint {
+    Solid([f32; 4]),
+    Gradient(Gradient),
+}
+
+#[derive(Debug, Clone)]
+pub struct Gradient {
+    pub kind: GradientKind,
+    pub extend: GradientExtend,
+    /// Offsets and linear colors, sorted by offset
+    pub stops: Vec<(f32, [f32; 4])>,
+    /// Transform applied to the gradient geometry, in font units
+    pub transform: Transform,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum GradientKind {
+    Linear {
+        start: [f32; 2],
+        end: [f32; 2],
+    },
+    Radial {
+        start_center: [f32; 2],
+        start_radius: f32,
+        end_center: [f32; 2],
+        end_radius: f32,
+    },
+    /// Angles are in radians, counter-clockwise
+    Sweep {
+        center: [f32; 2],
+        start_angle: f32,
+        end_angle: f32,
+    },
+}
+
+/// Resolve the layers of a `COLR` glyph with the colors of `palette`. Layers using the
+/// text color are filled with `foreground`. Returns None for glyphs without color layers.
+///
+/// The paint graph of COLRv1 glyphs is flattened into layers. Only the innermost glyph
+/// clip of a layer is kept, and composite modes are all drawn as source over.
+pub fn color_layers<'f>(face: &'f Face<'f>, glyph_id: GlyphId, palette: u16, foreground: [f32; 4]) -> Option<Vec<ColorLayer>> {
+    if !face.is_color_glyph(glyph_id) {
+        return None;
+    }
+
+    // Fall back to the default palette rather than dropping the glyph
+    let palette = match face.color_palettes() {
+        Some(count) if palette < count.get() => palette,
+        _ => 0,
+    };
+
+    let mut collector = LayerCollector {
+        face,
+        palette,
+        transform: Transform::default(),
+        transforms: Vec::new(),
+        outline: None,
+        clips: Vec::new(),
+        layers: Vec::new(),
+    };
+
+    face.paint_color_glyph(glyph_id, palette, linear_to_rgba(foreground), &mut collector)?;
+
+    Some(collector.layers)
+}
+
+// CPAL colors are sRGB encoded while the renderer works with linear colors
+pub fn srgb_to_linear(value: u8) -> f32 {
+    let value = value as f32 / 255.;
+
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub fn linear_to_srgb(value: f32) -> u8 {
+    let value = value.clamp(0., 1.);
+
+    let value = if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1. / 2.4) - 0.055
+    };
+
+    (value * 255.).round() as u8
+}
+
+pub fn rgba_to_linear(color: RgbaColor) -> [f32; 4] {
+    [
+        srgb_to_linear(color.red),
+        srgb_to_linear(color.green),
+        srgb_to_linear(color.blue),
+        color.alpha as f32 / 255.,
+    ]
+}
+
+fn linear_to_rgba(color: [f32; 4]) -> RgbaColor {
+    RgbaColor::new(
+        linear_to_srgb(color[0]),
+        linear_to_srgb(color[1]),
+        linear_to_srgb(color[2]),
+        (color[3].clamp(0., 1.) * 255.).round() as u8,
+    )
+}
+
+fn sorted_stops(stops: impl Iterator<Item = ColorStop>) -> Vec<(f32, [f32; 4])> {
+    let mut stops: Vec<(f32, [f32; 4])> = stops.map(|stop| (stop.stop_offset, rgba_to_linear(stop.color))).collect();
+
+    stops.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    stops
+}
+
+/// Inverse of an affine transform, None if it isn't invertible.
+pub fn invert(transform: Transform) -> Option<Transform> {
+    let Transform { a, b, c, d, e, f } = transform;
+
+    let determinant = a * d - b * c;
+
+    if determinant.abs() < f32::EPSILON {
+        return None;
+    }
+
+    Some(Transform::new(
+        d / determinant,
+        -b / determinant,
+        -c / determinant,
+        a / determinant,
+        (c * f - d * e) / determinant,
+        (b * e - a * f) / determinant,
+    ))
+}
+
+enum Clip {
+    Outline(GlyphId, Transform),
+    Box(ClipBox),
+    // Clip pushed without an outline, kept so that every pop matches its push
+    None,
+}
+
+// Walks the paint graph and records a layer for every paint applied to an outline
+struct LayerCollector<'f> {
+    face: &'f Face<'f>,
+    palette: u16,
+    transform: Transform,
+    transforms: Vec<Transform>,
+    // Last outline given by the font along with the transform in use at that time
+    outline: Option<(GlyphId, Transform)>,
+    clips: Vec<Clip>,
+    layers: Vec<ColorLayer>,
+}
+
+impl LayerCollector<'_> {
+    // Intersection of every clip box in the stack
+    fn clip_box(&self) -> Option<RectF> {
+        self.clips.iter().fold(None, |clip_box: Option<RectF>, clip| match (clip_box, clip) {
+            (None, Clip::Box(rect)) => Some(*rect),
+            (Some(current), Clip::Box(rect)) => Some(RectF {
+                x_min: current.x_min.max(rect.x_min),
+                y_min: current.y_min.max(rect.y_min),
+                x_max: current.x_max.min(rect.x_max),
+                y_max: current.y_max.min(rect.y_max),
+            }),
+            (clip_box, Clip::Outline(..) | Clip::None) => clip_box,
+        })
+    }
+}
+
+impl<'f> Painter<'f> for LayerCollector<'f> {
+    fn outline_glyph(&mut self, glyph_id: GlyphId) {
+        self.outline = Some((glyph_id, self.transform));
+    }
+
+    fn paint(&mut self, paint: Paint<'f>) {
+        // COLRv1 fills the innermost glyph clip, COLRv0 fills the last outline
+        let outline = self.clips.iter().rev().find_map(|clip| match clip {
+            Clip::Outline(glyph_id, transform) => Some((*glyph_id, *transform)),
+            Clip::Box(_) | Clip::None => None,
+        }).or_else(|| self.outline.take());
+
+        let Some((glyph_id, transform)) = outline else {
+            return;
+        };
+
+        let coords = self.face.variation_coordinates();
+
+        let gradient = |kind, extend, stops| LayerPaint::Gradient(Gradient {
+            kind,
+            extend,
+            stops,
+            transform: self.transform,
+        });
+
+        let paint = match paint {
+            Paint::Solid(color) => LayerPaint::Solid(rgba_to_linear(color)),
+            Paint::LinearGradient(linear) => {
+                // The gradient is perpendicular to p0p2, project p1 on the normal going through p0
+                let normal = [linear.y2 - linear.y0, linear.x0 - linear.x2];
+                let length = normal[0] * normal[0] + normal[1] * normal[1];
+                let end = if length == 0. {
+                    [linear.x1, linear.y1]
+                } else {
+                    let projection = ((linear.x1 - linear.x0) * normal[0] + (linear.y1 - linear.y0) * normal[1]) / length;
+                    [linear.x0 + projection * normal[0], linear.y0 + projection * normal[1]]
+                };
+
+                gradient(
+                    GradientKind::Linear { start: [linear.x0, linear.y0], end },
+                    linear.extend,
+                    sorted_stops(linear.stops(self.palette, coords)),
+                )
+            }
+            Paint::RadialGradient(radial) => gradient(
+                GradientKind::Radial {
+                    start_center: [radial.x0, radial.y0],
+                    start_radius: radial.r0,
+                    end_center: [radial.x1, radial.y1],
+                    end_radius: radial.r1,
+                },
+                radial.extend,
+                sorted_stops(radial.stops(self.palette, coords)),
+            ),
+            Paint::SweepGradient(sweep) => gradient(
+                // Angles are stored as multiples of 180 degrees
+                GradientKind::Sweep {
+                    center: [sweep.center_x, sweep.center_y],
+                    start_angle: sweep.start_angle * std::f32::consts::PI,
+                    end_angle: sweep.end_angle * std::f32::consts::PI,
+                },
+                sweep.extend,
+                sorted_stops(sweep.stops(self.palette, coords)),
+            ),
+        };
+
+        self.layers.push(ColorLayer {
+            glyph_id,
+            transform,
+            paint,
+            clip_box: self.clip_box(),
+        });
+    }
+
+    fn push_clip(&mut self) {
+        let clip = match self.outline.take() {
+            Some((glyph_id, transform)) => Clip::Outline(glyph_id, transform),
+            None => Clip::None,
+        };
+
+        self.clips.push(clip);
+    }
+
+    fn push_clip_box(&mut self, clipbox: ClipBox) {
+        self.clips.push(Clip::Box(clipbox));
+    }
+
+    fn pop_clip(&mut self) {
+        self.clips.pop();
+    }
+
+    // Layers are composited in order with source over
+    fn push_layer(&mut self, _mode: CompositeMode) {}
+
+    fn pop_layer(&mut self) {}
+
+    fn push_translate(&mut self, tx: f32, ty: f32) {
+        self.push_transform(Transform::new_translate(tx, ty));
+    }
+
+    fn push_scale(&mut self, sx: f32, sy: f32) {
+        self.push_transform(Transform::new(sx, 0., 0., sy, 0., 0.));
+    }
+
+    // Angles are stored as multiples of 180 degrees, counter-clockwise
+    fn push_rotate(&mut self, angle: f32) {
+        let (sin, cos) = (angle * std::f32::consts::PI).sin_cos();
+        self.push_transform(Transform::new(cos, sin, -sin, cos, 0., 0.));
+    }
+
+    fn push_skew(&mut self, skew_x: f32, skew_y: f32) {
+        let x = (-skew_x * std::f32::consts::PI).tan();
+        let y = (skew_y * std::f32::consts::PI).tan();
+        self.push_transform(Transform::new(1., y, x, 1., 0., 0.));
+    }
+
+    fn push_transform(&mut self, transform: Transform) {
+        self.transforms.push(self.transform);
+        self.transform = Transform::combine(self.transform, transform);
+    }
+
+    fn pop_transform(&mut self) {
+        if let Some(transform) = self.transforms.pop() {
+            self.transform = transform;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clips_without_outline_are_popped_on_their_own() {
+        let face = Face::parse(include_bytes!("../tests/fixtures/tiny.ttf"), 0).unwrap();
+
+        let mut collector = LayerCollector {
+            face: &face,
+            palette: 0,
+            transform: Transform::default(),
+            transforms: Vec::new(),
+            outline: None,
+            clips: Vec::new(),
+            layers: Vec::new(),
+        };
+
+        let clip_box = ClipBox { x_min: 0., y_min: 0., x_max: 10., y_max: 10. };
+
+        collector.push_clip_box(clip_box);
+        collector.push_clip();
+        collector.pop_clip();
+
+        collector.outline_glyph(GlyphId(1));
+        collector.paint(Paint::Solid(RgbaColor::new(255, 0, 0, 255)));
+
+        // The clip box pushed first still applies
+        let layer = &collector.layers[0];
+        assert_eq!(layer.glyph_id, GlyphId(1));
+        assert_eq!(layer.clip_box.map(|clip_box| clip_box.x_max), Some(10.));
+    }
+}
@@ -0,0 +1,293 @@
+use std::{fs, path::{Path, PathBuf}};
+
+use owned_ttf_parser::{Face, FaceParsingError};
+use swash::{FontDataRef, StringId};
+
+use crate::{loader::{self, LoadingError}, woff};
+
+// Directories where Linux distributions and users install fonts
+const SYSTEM_FONT_DIRS: [&str; 2] = ["/usr/share/fonts", "/usr/local/share/fonts"];
+const USER_FONT_DIRS: [&str; 2] = [".local/share/fonts", ".fonts"];
+
+const FONT_EXTENSIONS: [&str; 6] = ["ttf", "otf", "ttc", "otc", "woff", "woff2"];
+
+/// Location of a face on disk, to be loaded with `FontStore::load_handle`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FontHandle {
+    pub path: PathBuf,
+    /// Index of the face in font collections, 0 otherwise
+    pub index: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    Normal,
+    Italic,
+    Oblique,
+}
+
+/// Face indexed by the database, described by its `name` and `OS/2` tables.
+#[derive(Debug, Clone)]
+pub struct FaceEntry {
+    pub handle: FontHandle,
+    pub family: String,
+    pub style_name: Option<String>,
+    /// Weight class, from 100 (thin) to 900 (black)
+    pub weight: u16,
+    /// Width class, from 1 (ultra condensed) to 9 (ultra expanded), 5 is normal
+    pub stretch: u16,
+    pub style: Style,
+}
+
+/// Description of the face wanted, matched like CSS font properties.
+#[derive(Debug, Clone, Copy)]
+pub struct Query<'a> {
+    /// Families in order of preference, compared without case
+    pub families: &'a [&'a str],
+    pub weight: u16,
+    pub stretch: u16,
+    pub style: Style,
+}
+
+impl<'a> Query<'a> {
+    pub fn new(families: &'a [&'a str]) -> Self {
+        Self {
+            families,
+            weight: 400,
+            stretch: 5,
+            style: Style::Normal,
+        }
+    }
+}
+
+/// Index of the fonts installed in a set of directories.
+#[derive(Debug, Default)]
+pub struct FontDatabase {
+    faces: Vec<FaceEntry>,
+}
+
+impl FontDatabase {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Index the fonts of the standard system and user font directories.
+    pub fn load_system_fonts(&mut self) {
+        for dir in SYSTEM_FONT_DIRS {
+            self.load_dir(dir);
+        }
+
+        if let Some(home) = std::env::var_os("HOME") {
+            for dir in USER_FONT_DIRS {
+                self.load_dir(Path::new(&home).join(dir));
+            }
+        }
+    }
+
+    /// Index the fonts of a directory and its subdirectories. Files that can't be read or
+    /// parsed are skipped, and links to directories are not followed so that link loops
+    /// can't recurse forever. Returns the number of faces added.
+    pub fn load_dir(&mut self, dir: impl AsRef<Path>) -> usize {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return 0;
+        };
+
+        entries.flatten().map(|entry| {
+            let path = entry.path();
+
+            if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+                self.load_dir(&path)
+            } else if has_font_extension(&path) {
+                self.load_file(&path).unwrap_or(0)
+            } else {
+                0
+            }
+        }).sum()
+    }
+
+    /// Index every face of a font file, returns the number of faces added.
+    pub fn load_file(&mut self, path: impl AsRef<Path>) -> Result<usize, LoadingError> {
+        let path = path.as_ref();
+        let data = woff::decode(fs::read(path)?.into())?;
+
+        let collection = FontDataRef::new(&data).ok_or(LoadingError::Parse(FaceParsingError::UnknownMagic))?;
+        let count = self.faces.len();
+
+        for (index, font) in collection.fonts().enumerate() {
+            let Ok(face) = Face::parse(&data, index as u32) else {
+                continue;
+            };
+
+            let Some(family) = loader::localized_name(&font, &[StringId::TypographicFamily, StringId::Family]) else {
+                continue;
+            };
+
+            let style = if face.is_italic() {
+                Style::Italic
+            } else if face.is_oblique() {
+                Style::Oblique
+            } else {
+                Style::Normal
+            };
+
+            self.faces.push(FaceEntry {
+                handle: FontHandle {
+                    path: path.to_path_buf(),
+                    index,
+                },
+                family,
+                style_name: loader::localized_name(&font, &[StringId::TypographicSubFamily, StringId::SubFamily]),
+                weight: face.weight().to_number(),
+                stretch: face.width().to_number(),
+                style,
+            });
+        }
+
+        Ok(self.faces.len() - count)
+    }
+
+    pub fn faces(&self) -> &[FaceEntry] {
+        &self.faces
+    }
+
+    /// Names of the indexed families, sorted and without duplicates.
+    pub fn families(&self) -> Vec<&str> {
+        let mut families: Vec<&str> = self.faces.iter().map(|face| face.family.as_str()).collect();
+
+        families.sort_unstable();
+        families.dedup();
+
+        families
+    }
+
+    /// Find the face closest to the query with the CSS font matching algorithm: the first
+    /// family available is picked, then the closest stretch, style and weight in that order.
+    pub fn query(&self, query: &Query) -> Option<&FontHandle> {
+        let candidates: Vec<&FaceEntry> = query.families.iter()
+            .map(|family| self.faces.iter().filter(|face| face.family.eq_ignore_ascii_case(family)).collect::<Vec<_>>())
+            .find(|faces| !faces.is_empty())?;
+
+        let candidates = closest(candidates, |face| stretch_rank(query.stretch, face.stretch));
+        let candidates = closest(candidates, |face| style_rank(query.style, face.style));
+        let candidates = closest(candidates, |face| weight_rank(query.weight, face.weight));
+
+        candidates.first().map(|face| &face.handle)
+    }
+}
+
+fn has_font_extension(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| FONT_EXTENSIONS.iter().any(|font| font.eq_ignore_ascii_case(extension)))
+}
+
+// Keep the candidates with the lowest rank
+fn closest<K: Ord>(candidates: Vec<&FaceEntry>, rank: impl Fn(&FaceEntry) -> K) -> Vec<&FaceEntry> {
+    let Some(best) = candidates.iter().map(|face| rank(face)).min() else {
+        return candidates;
+    };
+
+    candidates.into_iter().filter(|face| rank(face) == best).collect()
+}
+
+// Condensed queries look at narrower widths first, expanded ones at wider widths first
+fn stretch_rank(desired: u16, available: u16) -> (u8, u16) {
+    let preferred = if desired <= 5 {
+        available <= desired
+    } else {
+        available >= desired
+    };
+
+    (if preferred { 0 } else { 1 }, desired.abs_diff(available))
+}
+
+fn style_rank(desired: Style, available: Style) -> u8 {
+    let order = match desired {
+        Style::Italic => [Style::Italic, Style::Oblique, Style::Normal],
+        Style::Oblique => [Style::Oblique, Style::Italic, Style::Normal],
+        Style::Normal => [Style::Normal, Style::Oblique, Style::Italic],
+    };
+
+    order.iter().position(|style| *style == available).unwrap_or(order.len()) as u8
+}
+
+// Weights between 400 and 500 look up to 500 first, then lighter and finally heavier.
+// Lighter queries prefer lighter faces, heavier queries prefer heavier faces.
+fn weight_rank(desired: u16, available: u16) -> (u8, u16) {
+    let distance = desired.abs_diff(available);
+
+    if (400..=500).contains(&desired) {
+        if (desired..=500).contains(&available) {
+            (0, distance)
+        } else if available < desired {
+            (1, distance)
+        } else {
+            (2, distance)
+        }
+    } else if desired < 400 {
+        (if available <= desired { 0 } else { 1 }, distance)
+    } else {
+        (if available >= desired { 0 } else { 1 }, distance)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stretch_prefers_exact_width() {
+        assert_eq!(stretch_rank(5, 5), (0, 0));
+        assert_eq!(stretch_rank(7, 7), (0, 0));
+        assert!(stretch_rank(7, 7) < stretch_rank(7, 8));
+        assert!(stretch_rank(3, 3) < stretch_rank(3, 2));
+    }
+
+    #[test]
+    fn stretch_looks_away_from_normal_first() {
+        // Condensed queries prefer narrower faces, expanded ones wider faces
+        assert!(stretch_rank(3, 1) < stretch_rank(3, 4));
+        assert!(stretch_rank(7, 9) < stretch_rank(7, 6));
+        assert!(stretch_rank(5, 4) < stretch_rank(5, 6));
+    }
+
+    #[test]
+    fn style_fallback_order() {
+        assert_eq!(style_rank(Style::Italic, Style::Italic), 0);
+        assert_eq!(style_rank(Style::Italic, Style::Oblique), 1);
+        assert_eq!(style_rank(Style::Italic, Style::Normal), 2);
+        assert_eq!(style_rank(Style::Oblique, Style::Italic), 1);
+        assert_eq!(style_rank(Style::Normal, Style::Oblique), 1);
+        assert_eq!(style_rank(Style::Normal, Style::Italic), 2);
+    }
+
+    #[test]
+    fn weight_between_400_and_500() {
+        // Up to 500 first, then lighter, then heavier
+        assert!(weight_rank(400, 500) < weight_rank(400, 300));
+        assert!(weight_rank(400, 300) < weight_rank(400, 600));
+        assert!(weight_rank(450, 400) < weight_rank(450, 600));
+        assert_eq!(weight_rank(400, 400), (0, 0));
+    }
+
+    #[test]
+    fn weight_outside_400_and_500() {
+        assert!(weight_rank(300, 100) < weight_rank(300, 400));
+        assert!(weight_rank(700, 900) < weight_rank(700, 600));
+        assert!(weight_rank(700, 800) < weight_rank(700, 900));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn load_dir_skips_link_loops() {
+        let dir = std::env::temp_dir().join(format!("wgpu-font-renderer-loop-{}", std::process::id()));
+
+        fs::create_dir_all(&dir).unwrap();
+        std::os::unix::fs::symlink(&dir, dir.join("loop")).unwrap();
+
+        let added = FontDatabase::new().load_dir(&dir);
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(added, 0);
+    }
+}
@@ -0,0 +1,253 @@
+use std::{fs, hash::Hasher, io, path::{Path, PathBuf}};
+
+use owned_ttf_parser::{GlyphId, Rect};
+
+use crate::loader::{Font, PackedGlyph, PackedOutlines};
+
+// Files holding the outlines of the preset glyphs of a font, packed the way they are
+// allocated in the atlas, so that later runs skip the outline extraction and upload the
+// whole preset as a single span. Little endian layout:
+//
+// magic, version: u32, font hash: u64, preset hash: u64, glyph count: u32, texel count: u32
+// per glyph: id: u16, bbox: 4 x i16, descent: i16, y_offset: i16, left side bearing: i16, offset: u32, texels: u32
+// curves of every glyph, grouped in bands, back to back as f32 at the offsets of their glyph
+const MAGIC: [u8; 4] = *b"WFRG";
+// Bump whenever the curve layout or the glyph metadata change
+const VERSION: u32 = 1;
+
+const HEADER_SIZE: usize = 32;
+const GLYPH_RECORD_SIZE: usize = 24;
+
+/// 64 bits FNV-1a, stable across runs and Rust versions unlike the default hasher.
+pub(crate) struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for Fnv {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Outlines of the preset glyphs of `font`, read from the cache in `dir` when it has them.
+/// Otherwise they are extracted and written to the cache, failing to write it is ignored
+/// as the cache only speeds up the next runs.
+pub(crate) fn preset_outlines(dir: &Path, font: &Font, cache_preset: &str) -> PackedOutlines {
+    let font_hash = font.content_hash();
+
+    let mut hasher = Fnv::default();
+    hasher.write(cache_preset.as_bytes());
+    let preset_hash = hasher.finish();
+
+    let path = dir.join(format!("{font_hash:016x}-{preset_hash:016x}.glyphs"));
+
+    if let Some(outlines) = fs::read(&path).ok().and_then(|data| decode(&data, font_hash, preset_hash)) {
+        return outlines;
+    }
+
+    let outlines = PackedOutlines::new(font.preset_outlines(cache_preset));
+
+    let _ = write(dir, &path, &encode(&outlines, font_hash, preset_hash));
+
+    outlines
+}
+
+// Write to a temporary file first so that other processes never read a partial file
+fn write(dir: &Path, path: &PathBuf, data: &[u8]) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+
+    let temporary = path.with_extension(format!("{}.tmp", std::process::id()));
+
+    fs::write(&temporary, data)?;
+    fs::rename(&temporary, path)
+}
+
+fn encode(outlines: &PackedOutlines, font_hash: u64, preset_hash: u64) -> Vec<u8> {
+    let mut data = Vec::with_capacity(HEADER_SIZE + outlines.glyphs.len() * GLYPH_RECORD_SIZE + outlines.curves.len() * 4);
+
+    data.extend_from_slice(&MAGIC);
+    data.extend_from_slice(&VERSION.to_le_bytes());
+    data.extend_from_slice(&font_hash.to_le_bytes());
+    data.extend_from_slice(&preset_hash.to_le_bytes());
+    data.extend_from_slice(&(outlines.glyphs.len() as u32).to_le_bytes());
+    data.extend_from_slice(&(outlines.curves.len() as u32).to_le_bytes());
+
+    for glyph in &outlines.glyphs {
+        data.extend_from_slice(&glyph.glyph_id.0.to_le_bytes());
+
+        for value in [
+            glyph.bbox.x_min,
+            glyph.bbox.y_min,
+            glyph.bbox.x_max,
+            glyph.bbox.y_max,
+            glyph.descent,
+            glyph.y_offset,
+            glyph.left_side_bearing,
+        ] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+
+        data.extend_from_slice(&glyph.offset.to_le_bytes());
+        data.extend_from_slice(&glyph.size.to_le_bytes());
+    }
+
+    for value in &outlines.curves {
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    data
+}
+
+// None if the file is truncated, was written for another version, font or preset, or if
+// its glyphs aren't packed back to back
+fn decode(data: &[u8], font_hash: u64, preset_hash: u64) -> Option<PackedOutlines> {
+    let mut reader = Reader { data };
+
+    if reader.bytes(4)? != MAGIC
+        || reader.u32()? != VERSION
+        || reader.u64()? != font_hash
+        || reader.u64()? != preset_hash {
+        return None;
+    }
+
+    let count = reader.u32()? as usize;
+    let texels = reader.u32()?;
+
+    let mut glyphs = Vec::with_capacity(count.min(data.len() / GLYPH_RECORD_SIZE));
+    let mut end = 0u32;
+
+    for _ in 0..count {
+        let glyph = PackedGlyph {
+            glyph_id: GlyphId(reader.u16()?),
+            bbox: Rect {
+                x_min: reader.i16()?,
+                y_min: reader.i16()?,
+                x_max: reader.i16()?,
+                y_max: reader.i16()?,
+            },
+            descent: reader.i16()?,
+            y_offset: reader.i16()?,
+            left_side_bearing: reader.i16()?,
+            offset: reader.u32()?,
+            size: reader.u32()?,
+        };
+
+        if glyph.offset != end {
+            return None;
+        }
+
+        end = end.checked_add(glyph.size)?;
+        glyphs.push(glyph);
+    }
+
+    if end != texels {
+        return None;
+    }
+
+    let curves = reader.bytes((texels as usize).checked_mul(4)?)?
+        .chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .collect();
+
+    Some(PackedOutlines { curves, glyphs })
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, count: usize) -> Option<&'a [u8]> {
+        if count > self.data.len() {
+            return None;
+        }
+
+        let (bytes, rest) = self.data.split_at(count);
+        self.data = rest;
+
+        Some(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        self.bytes(N)?.try_into().ok()
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.array().map(u16::from_le_bytes)
+    }
+
+    fn i16(&mut self) -> Option<i16> {
+        self.array().map(i16::from_le_bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.array().map(u32::from_le_bytes)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.array().map(u64::from_le_bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outlines() -> PackedOutlines {
+        let glyph = |glyph_id, offset, size| PackedGlyph {
+            glyph_id: GlyphId(glyph_id),
+            bbox: Rect { x_min: -1, y_min: -2, x_max: 30, y_max: 40 },
+            descent: -5,
+            y_offset: 3,
+            left_side_bearing: 7,
+            offset,
+            size,
+        };
+
+        PackedOutlines {
+            curves: (0..20).map(|value| value as f32 * 0.5).collect(),
+            glyphs: vec![glyph(3, 0, 12), glyph(9, 12, 8)],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let outlines = outlines();
+        let decoded = decode(&encode(&outlines, 1, 2), 1, 2).unwrap();
+
+        assert_eq!(decoded.curves, outlines.curves);
+        assert_eq!(decoded.glyphs.len(), 2);
+        assert_eq!(decoded.glyphs[1].glyph_id, GlyphId(9));
+        assert_eq!([decoded.glyphs[1].offset, decoded.glyphs[1].size], [12, 8]);
+        assert_eq!(decoded.glyphs[0].bbox.y_max, 40);
+        assert_eq!(decoded.glyphs[0].left_side_bearing, 7);
+    }
+
+    #[test]
+    fn other_fonts_and_truncated_files_are_ignored() {
+        let data = encode(&outlines(), 1, 2);
+
+        assert!(decode(&data, 3, 2).is_none());
+        assert!(decode(&data, 1, 3).is_none());
+        assert!(decode(&data[..data.len() - 1], 1, 2).is_none());
+    }
+
+    #[test]
+    fn glyphs_must_be_packed_back_to_back() {
+        let mut outlines = outlines();
+        outlines.glyphs[1].offset = 13;
+
+        assert!(decode(&encode(&outlines, 1, 2), 1, 2).is_none());
+    }
+}
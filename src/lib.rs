@@ -1,10 +1,20 @@
 mod loader;
 mod store;
 mod atlas;
+mod bands;
+mod color;
+mod bitmap;
+mod database;
+mod disk_cache;
+mod metrics;
 mod renderer;
 mod typewriter;
 mod ortho;
+mod woff;
+pub use atlas::{AtlasBudget, AtlasConfig};
+pub use database::{FaceEntry, FontDatabase, FontHandle, Query, Style};
 pub use renderer::TextRenderer;
 pub use store::FontStore;
-pub use loader::LoadingError;
-pub use typewriter::TypeWriter;
\ No newline at end of file
+pub use loader::{FaceInfo, LoadingError, PreparedFont, Synthesis, Variation};
+pub use metrics::{FontMetrics, FontNames};
+pub use typewriter::{FillRule, Paragraph, Run, TypeWriter};
\ No newline at end of file
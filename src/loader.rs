@@ -1,181 +1,997 @@
-use std::{collections::HashMap, fmt, io::Read};
-use owned_ttf_parser::{AsFaceRef, GlyphId, OutlineBuilder, OwnedFace, Rect};
-use swash::{CacheKey, FontRef};
+use std::{collections::HashMap, fmt, hash::Hasher, io, path::Path, sync::Arc};
+use owned_ttf_parser::{Face, FaceParsingError, GlyphId, OutlineBuilder, Rect, Tag};
+use swash::{tag_from_bytes, CacheKey, FontDataRef, FontRef, Setting, StringId};
 
-use crate::atlas::{allocation::Allocation, Atlas};
+use crate::{atlas::{allocation::Allocation, Atlas}, bands, bitmap::{BitmapGlyph, GlyphBitmap}, color::{self, ColorLayer}, disk_cache, metrics::{FontMetrics, FontNames}, woff};
 
 #[derive(Debug)]
 pub struct Glyph {
-    pub curves: Vec<f32>,
     pub allocation: Allocation,
     pub bbox: Rect,
     pub descent: i16,
     pub y_offset: i16,
     pub left_side_bearing: i16,
+    /// Frame in which the glyph was last prepared, used to evict glyphs when the atlas is full
+    pub last_used: u64,
+}
+
+impl Glyph {
+    pub fn new(outline: GlyphOutline, allocation: Allocation, last_used: u64) -> Self {
+        Self {
+            allocation,
+            bbox: outline.bbox,
+            descent: outline.descent,
+            y_offset: outline.y_offset,
+            left_side_bearing: outline.left_side_bearing,
+            last_used,
+        }
+    }
+
+    fn packed(glyph: &PackedGlyph, allocation: Allocation) -> Self {
+        Self {
+            allocation,
+            bbox: glyph.bbox,
+            descent: glyph.descent,
+            y_offset: glyph.y_offset,
+            left_side_bearing: glyph.left_side_bearing,
+            last_used: 0,
+        }
+    }
 }
 
+/// Glyph curves and metrics extracted from the font, before they are uploaded to the atlas.
+#[derive(Debug)]
+pub struct GlyphOutline {
+    pub curves: Vec<f32>,
+    pub bbox: Rect,
+    pub descent: i16,
+    pub y_offset: i16,
+    pub left_side_bearing: i16,
+}
+
+// Face parsed from the font data it borrows, the data is shared between the instances of a font
+self_cell::self_cell!(
+    struct SharedFace {
+        owner: Arc<[u8]>,
+
+        #[covariant]
+        dependent: Face,
+    }
+);
+
 pub struct Font {
-    data: Vec<u8>,
-    pub face: OwnedFace,
+    index: usize,
+    face: SharedFace,
     pub offset: u32,
     pub key: CacheKey,
+    pub variations: Vec<Variation>,
+    pub synthesis: Synthesis,
     pub glyph_cache: HashMap<GlyphId, Glyph>,
+    /// Glyphs without outlines drawn from their embedded images
+    pub bitmap_cache: HashMap<GlyphId, BitmapGlyph>,
+}
+
+/// Coordinate on one axis of a variable font, e.g. `Variation::new(*b"wght", 700.)`.
+/// Axes missing from the font are ignored.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Variation {
+    pub axis: [u8; 4],
+    pub value: f32,
+}
+
+impl Variation {
+    pub fn new(axis: [u8; 4], value: f32) -> Self {
+        Self { axis, value }
+    }
+}
+
+/// Styles emulated from the outlines of a regular face, for families that ship
+/// without bold or italic faces.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Synthesis {
+    /// Width added to the strokes, as a fraction of the em
+    pub embolden: f32,
+    /// Horizontal shift per unit of height, the tangent of the slant angle
+    pub skew: f32,
+}
+
+impl Synthesis {
+    /// Same strength as FreeType's emboldening
+    pub const BOLD: Self = Self { embolden: 1. / 24., skew: 0. };
+    /// Slant of 12 degrees
+    pub const OBLIQUE: Self = Self { embolden: 0., skew: 0.2126 };
+    pub const BOLD_OBLIQUE: Self = Self { embolden: Self::BOLD.embolden, skew: Self::OBLIQUE.skew };
+
+    pub fn is_none(&self) -> bool {
+        self.embolden == 0. && self.skew == 0.
+    }
+
+    /// Width added to the advance of glyphs rendered at `size` pixels per em
+    pub fn advance(&self, size: f32) -> f32 {
+        self.embolden * size
+    }
+}
+
+/// Description of one face stored in a font file. Plain `.ttf`/`.otf` files
+/// hold a single face while collections (`.ttc`/`.otc`) can hold many.
+#[derive(Debug, Clone)]
+pub struct FaceInfo {
+    pub index: usize,
+    pub family: Option<String>,
+    pub style: Option<String>,
 }
 
 type Result<T> = std::result::Result<T, LoadingError>;
 
 #[derive(Debug)]
 pub enum LoadingError {
-    FileNotFound,
-    InvalidFile,
+    /// The font file couldn't be read
+    Io(io::Error),
+    /// The data isn't a valid font or one of its required tables is malformed
+    Parse(FaceParsingError),
+    /// The font holds `count` faces and the requested index is outside of them
+    FaceIndexOutOfRange { index: usize, count: usize },
+    /// The face has neither `glyf`, `CFF`/`CFF2` outlines nor embedded bitmaps
+    UnsupportedOutlineFormat,
+    /// No font is loaded with the given key
+    UnknownFont,
+    /// The atlas budget is reached
+    AtlasExhausted,
+    /// The glyph doesn't fit in a single atlas layer
+    GlyphTooLarge,
+    /// The atlas would need more layers than its configuration or the device allow
+    TextureLimitExceeded { layers: u32, limit: u32 },
 }
 
 impl fmt::Display for LoadingError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            LoadingError::FileNotFound =>
-                write!(f, "TTF Font file not found"),
-            LoadingError::InvalidFile =>
-                write!(f, "TTF Font file provided is invalid"),
+        match self {
+            LoadingError::Io(error) =>
+                write!(f, "couldn't read the font file: {error}"),
+            LoadingError::Parse(error) =>
+                write!(f, "couldn't parse the font: {error}"),
+            LoadingError::FaceIndexOutOfRange { index, count } =>
+                write!(f, "face index {index} is out of range, the font has {count} faces"),
+            LoadingError::UnsupportedOutlineFormat =>
+                write!(f, "the font has no supported glyph outlines or images"),
+            LoadingError::UnknownFont =>
+                write!(f, "no font is loaded with this key"),
+            LoadingError::AtlasExhausted =>
+                write!(f, "no space left in the glyph atlas"),
+            LoadingError::GlyphTooLarge =>
+                write!(f, "the glyph doesn't fit in a layer of the glyph atlas"),
+            LoadingError::TextureLimitExceeded { layers, limit } =>
+                write!(f, "the glyph atlas needs {layers} layers but is limited to {limit}"),
         }
     }
 }
 
+impl std::error::Error for LoadingError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadingError::Io(error) => Some(error),
+            LoadingError::Parse(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for LoadingError {
+    fn from(error: io::Error) -> Self {
+        LoadingError::Io(error)
+    }
+}
+
+impl From<FaceParsingError> for LoadingError {
+    fn from(error: FaceParsingError) -> Self {
+        LoadingError::Parse(error)
+    }
+}
+
+/// Outlines of a preset with their curves packed back to back, laid out the way they are
+/// allocated in the atlas. This is the layout of the disk cache, so that a cached preset
+/// is uploaded as a single span without being packed again.
+#[derive(Debug, Default)]
+pub(crate) struct PackedOutlines {
+    pub curves: Vec<f32>,
+    /// By increasing offset, every glyph starting where the previous one ends
+    pub glyphs: Vec<PackedGlyph>,
+}
+
+#[derive(Debug)]
+pub(crate) struct PackedGlyph {
+    pub glyph_id: GlyphId,
+    pub bbox: Rect,
+    pub descent: i16,
+    pub y_offset: i16,
+    pub left_side_bearing: i16,
+    /// Texel offset of the curves of the glyph in `PackedOutlines::curves`
+    pub offset: u32,
+    pub size: u32,
+}
+
+impl PackedOutlines {
+    pub fn new(outlines: Vec<(GlyphId, GlyphOutline)>) -> Self {
+        let mut packed = Self::default();
+
+        for (glyph_id, outline) in outlines {
+            packed.glyphs.push(PackedGlyph {
+                glyph_id,
+                bbox: outline.bbox,
+                descent: outline.descent,
+                y_offset: outline.y_offset,
+                left_side_bearing: outline.left_side_bearing,
+                offset: packed.curves.len() as u32,
+                size: outline.curves.len() as u32,
+            });
+
+            packed.curves.extend_from_slice(&outline.curves);
+        }
+
+        packed
+    }
+}
+
+/// Font parsed and outlined on the CPU, waiting to be committed to a `FontStore`.
+/// Preparing is the slow part of loading a font and doesn't need the GPU, so it can
+/// run on a worker thread while the render thread only uploads the curves.
+pub struct PreparedFont {
+    pub(crate) font: Font,
+    pub(crate) outlines: PackedOutlines,
+}
+
+impl PreparedFont {
+    /// Read and prepare the face at `index` of a font file. Outlines are read from and
+    /// written to `disk_cache` if a directory is given, see `FontStore::set_disk_cache`.
+    pub fn from_file(path: impl AsRef<Path>, index: usize, cache_preset: &str, disk_cache: Option<&Path>) -> Result<Self> {
+        let data = std::fs::read(path)?;
+
+        Self::from_bytes(data, index, cache_preset, disk_cache)
+    }
+
+    pub fn from_bytes(data: impl Into<Arc<[u8]>>, index: usize, cache_preset: &str, disk_cache: Option<&Path>) -> Result<Self> {
+        let font = Font::new(data, index, &[], Synthesis::default())?;
+
+        Ok(Self::new(font, cache_preset, disk_cache))
+    }
+
+    pub(crate) fn new(font: Font, cache_preset: &str, disk_cache: Option<&Path>) -> Self {
+        let outlines = match disk_cache {
+            Some(dir) => disk_cache::preset_outlines(dir, &font, cache_preset),
+            None => PackedOutlines::new(font.preset_outlines(cache_preset)),
+        };
+
+        Self { font, outlines }
+    }
+
+    /// Key the font will have once committed
+    pub fn key(&self) -> CacheKey {
+        self.font.key
+    }
+}
+
 impl Font {
-    pub fn from_file(
-        device: &wgpu::Device,
-        encoder: &mut wgpu::CommandEncoder,
-        queue: &wgpu::Queue,
-        path: &str,
+    /// Parse the face at `index` without touching the GPU, the glyph cache starts empty.
+    pub fn new(
+        data: impl Into<Arc<[u8]>>,
         index: usize,
-        cache_preset: &str,
-        atlas: &mut Atlas
+        variations: &[Variation],
+        synthesis: Synthesis
     ) -> Result<Font> {
-        // Read the font file as bytes
-        let data = std::fs::read(path).or(Err(LoadingError::FileNotFound))?;
+        let data = woff::decode(data.into())?;
+
+        let collection = FontDataRef::new(&data).ok_or(LoadingError::Parse(FaceParsingError::UnknownMagic))?;
+
+        if index >= collection.len() {
+            return Err(LoadingError::FaceIndexOutOfRange { index, count: collection.len() });
+        }
+
+        // Generate struct that hold TTF face tables
+        let mut face = SharedFace::try_new(data.clone(), |data| Face::parse(data, index as u32))?;
+
+        if !has_glyph_data(face.borrow_dependent()) {
+            return Err(LoadingError::UnsupportedOutlineFormat);
+        }
+
         // Create a temporary font reference for the font available in the file at `index`.
-        // This will do some basic validation, compute the necessary offset
-        // and generate a fresh cache key for us.
-        let font = FontRef::from_index(&data, index).ok_or(LoadingError::InvalidFile)?;
+        // This will compute the necessary offset and generate a fresh cache key for us.
+        let font = FontRef::from_index(&data, index).ok_or(LoadingError::Parse(FaceParsingError::MalformedFont))?;
         let (offset, key) = (font.offset, font.key);
 
-        // Generate struct that hold TTF face tables
-        let face = OwnedFace::from_vec(data.clone(), index as u32).or(Err(LoadingError::InvalidFile))?;
+        // Select the instance of variable fonts, outlines are extracted with these coordinates
+        face.with_dependent_mut(|_, face| {
+            for variation in variations {
+                face.set_variation(Tag::from_bytes(&variation.axis), variation.value);
+            }
+        });
+
+        Ok(Self {
+            index,
+            face,
+            offset,
+            key,
+            variations: variations.to_vec(),
+            synthesis,
+            glyph_cache: HashMap::new(),
+            bitmap_cache: HashMap::new(),
+        })
+    }
+
+    /// Create another instance of this variable font with its own key and an empty glyph
+    /// cache. The font data is shared between instances.
+    pub fn instance(&self, variations: &[Variation]) -> Result<Font> {
+        Self::new(self.face.borrow_owner().clone(), self.index, variations, self.synthesis)
+    }
+
+    /// Create a synthetic bold or oblique variant of this font with its own key and an empty
+    /// glyph cache. Outlines are emboldened and slanted, embedded images are left as they are.
+    pub fn synthetic(&self, synthesis: Synthesis) -> Result<Font> {
+        Self::new(self.face.borrow_owner().clone(), self.index, &self.variations, synthesis)
+    }
+
+    /// Extract the outlines of the characters of `cache_preset`, without touching the GPU.
+    pub fn preset_outlines(&self, cache_preset: &str) -> Vec<(GlyphId, GlyphOutline)> {
+        let face = self.face();
+        let mut outlines: Vec<(GlyphId, GlyphOutline)> = Vec::new();
+
+        for code_point in cache_preset.chars() {
+            let Some(glyph_id) = face.glyph_index(code_point) else {
+                continue;
+            };
+
+            if outlines.iter().any(|(id, _)| *id == glyph_id) {
+                continue;
+            }
+
+            if let Some(outline) = self.outline_glyph(glyph_id) {
+                outlines.push((glyph_id, outline));
+            }
+        }
+
+        outlines
+    }
+
+    /// Upload outlines to the atlas in bulk and add them to the glyph cache. If they don't
+    /// fit in the atlas, none of them is added and the error is returned.
+    pub(crate) fn upload_outlines(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        outlines: &PackedOutlines,
+        atlas: &mut Atlas
+    ) -> Result<()> {
+        let sizes: Vec<u32> = outlines.glyphs.iter().map(|glyph| glyph.size).collect();
+
+        let allocations = atlas.upload_many(&sizes, bytemuck::cast_slice(&outlines.curves), device, encoder)?;
+
+        for (glyph, allocation) in outlines.glyphs.iter().zip(allocations) {
+            if let Some(previous) = self.glyph_cache.insert(glyph.glyph_id, Glyph::packed(glyph, allocation)) {
+                atlas.deallocate(previous.allocation);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Identify the data, face and instance of this font across runs, for the disk cache
+    pub(crate) fn content_hash(&self) -> u64 {
+        let mut hasher = disk_cache::Fnv::default();
 
-        // Generate glyph cache for each glyph present in the font file
-        let glyph_cache = create_glyph_cache(device, encoder, queue, &face, cache_preset, atlas);
+        hasher.write(self.face.borrow_owner());
+        hasher.write_u64(self.index as u64);
+
+        for variation in &self.variations {
+            hasher.write(&variation.axis);
+            hasher.write_u32(variation.value.to_bits());
+        }
+
+        hasher.write_u32(self.synthesis.embolden.to_bits());
+        hasher.write_u32(self.synthesis.skew.to_bits());
+
+        hasher.finish()
+    }
+
+    /// Variation settings in the form expected by the swash shaper.
+    pub fn variation_settings(&self) -> impl Iterator<Item = Setting<f32>> + '_ {
+        self.variations.iter().map(|variation| Setting {
+            tag: tag_from_bytes(&variation.axis),
+            value: variation.value,
+        })
+    }
+
+    /// Whether the glyph has an outline or an image that isn't cached yet.
+    pub fn needs_caching(&self, glyph_id: GlyphId) -> bool {
+        let face = self.face();
+
+        !self.glyph_cache.contains_key(&glyph_id)
+            && !self.bitmap_cache.contains_key(&glyph_id)
+            && (face.glyph_bounding_box(glyph_id).is_some() || face.glyph_raster_image(glyph_id, u16::MAX).is_some())
+    }
+
+    pub fn outline_glyph(&self, glyph_id: GlyphId) -> Option<GlyphOutline> {
+        GlyphOutline::new(self.face(), glyph_id, self.synthesis)
+    }
+
+    /// Embedded image of the glyph, for fonts such as emoji fonts that don't have outlines.
+    pub fn bitmap_glyph(&self, glyph_id: GlyphId) -> Option<GlyphBitmap> {
+        GlyphBitmap::new(self.face(), glyph_id)
+    }
 
-        Ok(Self { data, face, offset, key, glyph_cache })
+    /// Layers of a color glyph resolved with the colors of `palette`, None for regular glyphs.
+    pub fn color_layers(&self, glyph_id: GlyphId, palette: u16, foreground: [f32; 4]) -> Option<Vec<ColorLayer>> {
+        color::color_layers(self.face(), glyph_id, palette, foreground)
+    }
+
+    /// Number of color palettes of the font, 0 if it has no color glyphs.
+    pub fn palette_count(&self) -> u16 {
+        self.face().color_palettes().map_or(0, |count| count.get())
+    }
+
+    /// Tables of the face, with the coordinates of the instance for variable fonts.
+    pub fn face(&self) -> &Face<'_> {
+        self.face.borrow_dependent()
+    }
+
+    /// Vertical metrics in font units, see `FontMetrics::scale` for pixels.
+    /// Variable font instances get the metrics of their coordinates.
+    pub fn metrics(&self) -> FontMetrics {
+        FontMetrics::new(self.face())
+    }
+
+    pub fn names(&self) -> FontNames {
+        FontNames::new(&self.as_ref())
     }
 
     // Create the transient font reference to access swash features
-    pub fn as_ref(&self) -> FontRef {
+    pub fn as_ref(&self) -> FontRef<'_> {
         FontRef {
-            data: &self.data,
+            data: self.face.borrow_owner(),
             offset: self.offset,
             key: self.key,
         }
     }
 }
 
+/// List every face available in the font data, in collection order.
+pub fn faces(data: &[u8]) -> Result<Vec<FaceInfo>> {
+    let data = woff::decode(data.into())?;
+    let collection = FontDataRef::new(&data).ok_or(LoadingError::Parse(FaceParsingError::UnknownMagic))?;
+
+    Ok(collection.fonts().enumerate().map(|(index, font)| FaceInfo {
+        index,
+        family: localized_name(&font, &[StringId::TypographicFamily, StringId::Family]),
+        style: localized_name(&font, &[StringId::TypographicSubFamily, StringId::SubFamily]),
+    }).collect())
+}
+
+// Look the name table up for the first id available, English first and any language otherwise
+pub(crate) fn localized_name(font: &FontRef, ids: &[StringId]) -> Option<String> {
+    let strings = font.localized_strings();
+
+    ids.iter()
+        .find_map(|id| strings.find_by_id(*id, Some("en")).or_else(|| strings.find_by_id(*id, None)))
+        .map(|name| name.to_string())
+}
+
+// Whether glyphs can be drawn from outlines or embedded images. SVG-only fonts can't.
+fn has_glyph_data(face: &Face) -> bool {
+    let tables = face.tables();
+
+    tables.glyf.is_some()
+        || tables.cff.is_some()
+        || tables.cff2.is_some()
+        || tables.sbix.is_some()
+        || tables.cbdt.is_some()
+        || tables.ebdt.is_some()
+}
+
+impl GlyphOutline {
+    // Extract the glyph outline, returns None for glyphs without any curve
+    pub fn new(face: &Face, glyph_id: GlyphId, synthesis: Synthesis) -> Option<Self> {
+        let ascender = face.ascender();
+
+        let mut bbox = face.glyph_bounding_box(glyph_id)?;
 
-fn create_glyph_cache(
-    device: &wgpu::Device,
-    encoder: &mut wgpu::CommandEncoder,
-    queue: &wgpu::Queue,
-    face: &OwnedFace,
-    cache_preset: &str,
-    atlas: &mut Atlas
-) -> HashMap<GlyphId, Glyph> {
-    let mut glyph_cache = HashMap::new();
+        let mut builder = BezierBuilder::new();
 
-    let face = face.as_face_ref();
+        face.outline_glyph(glyph_id, &mut builder);
 
-    let ascender = face.ascender();
+        // CFF outlines don't close their last contour
+        builder.close();
 
-    for code_point in cache_preset.chars() { 
-        if let Some(glyph_id) = face.glyph_index(code_point) {
-            if let Some(bbox) = face.glyph_bounding_box(glyph_id) {
-                let height = bbox.height();
-                let left_side_bearing = bbox.x_min;
-    
-                let (descent, distance_from_baseline) = if bbox.y_min <= 0 {
-                    (bbox.y_min, 0)
+        if !synthesis.is_none() {
+            builder.embolden(synthesis.embolden * face.units_per_em() as f32);
+            builder.skew(synthesis.skew);
+            bbox = builder.bounds()?;
+        }
+
+        let height = bbox.height();
+        let left_side_bearing = bbox.x_min;
+
+        let (descent, distance_from_baseline) = if bbox.y_min <= 0 {
+            (bbox.y_min, 0)
+        } else {
+            (0, bbox.y_min)
+        };
+
+        let total_height = height + descent + distance_from_baseline;
+        let y_offset = ascender - distance_from_baseline - height;
+
+        let curves = builder.curves(total_height as f32);
+
+        if curves.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            curves: bands::build(&curves),
+            bbox,
+            descent,
+            y_offset,
+            left_side_bearing,
+        })
+    }
+
+    pub fn upload(
+        &self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        atlas: &mut Atlas
+    ) -> Result<Allocation> {
+        let curves_count = self.curves.len() as u32;
+
+        atlas.upload(curves_count, bytemuck::cast_slice(&self.curves), device, encoder)
+    }
+}
+
+// Maximum distance in font units between a cubic curve and the quadratics replacing it
+const CUBIC_TOLERANCE: f32 = 0.5;
+const CUBIC_MAX_SUBDIVISIONS: u32 = 8;
+
+// Points closer than this in font units are considered the same when emboldening
+const EMBOLDEN_EPSILON: f32 = 1e-3;
+
+// Also split curves at their x extrema, for the vertical ray of the shader
+const SPLIT_X_EXTREMA: bool = true;
+
+#[derive(Debug, Clone, Copy)]
+struct OutlinePoint {
+    position: [f32; 2],
+    /// Off curve points are the control points of quadratic curves
+    on_curve: bool,
+}
+
+// Collect the contours of a glyph in font units. Curves are emitted once the whole outline
+// is known, so that it can be emboldened or slanted first.
+struct BezierBuilder {
+    contours: Vec<Vec<OutlinePoint>>,
+}
+
+impl BezierBuilder {
+    pub fn new() -> Self {
+        Self {
+            contours: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, position: [f32; 2], on_curve: bool) {
+        if let Some(contour) = self.contours.last_mut() {
+            contour.push(OutlinePoint { position, on_curve });
+        }
+    }
+
+    fn last_position(&self) -> [f32; 2] {
+        self.contours.last()
+            .and_then(|contour| contour.last())
+            .map_or([0., 0.], |point| point.position)
+    }
+
+    // Curves in the layout read by the shader, with y going down from `total_height`
+    pub fn curves(&self, total_height: f32) -> Vec<f32> {
+        let mut curves = Vec::new();
+
+        for contour in &self.contours {
+            let Some(first) = contour.first() else {
+                continue;
+            };
+
+            let [mut x0, mut y0] = first.position;
+            let mut points = contour[1..].iter();
+
+            while let Some(point) = points.next() {
+                let [cx, cy] = point.position;
+
+                let [x1, y1] = if point.on_curve {
+                    [cx, cy]
                 } else {
-                    (0, bbox.y_min)
+                    match points.next() {
+                        Some(end) => end.position,
+                        None => break,
+                    }
                 };
-    
-                let total_height = height + descent + distance_from_baseline;
-                let y_offset = ascender - distance_from_baseline - height;
-    
-                let mut builder = BezierBuilder::new(total_height as f32);
-    
-                face.outline_glyph(glyph_id, &mut builder);
-
-                let curves_count = builder.curves.len() as u32;
-    
-                let bytes = unsafe {
-                    std::slice::from_raw_parts(builder.curves.as_ptr() as *const u8, builder.curves.len() * 4)
+
+                // Zero length segments don't change the winding, skip them to save atlas space
+                let degenerate = [x0, y0] == [x1, y1] && [x0, y0] == [cx, cy];
+
+                if !degenerate {
+                    let [p0, control, p1] = [[x0, total_height - y0], [cx, total_height - cy], [x1, total_height - y1]];
+
+                    push_monotonic(&mut curves, [p0, control, p1], 1);
+                }
+
+                [x0, y0] = [x1, y1];
+            }
+        }
+
+        curves
+    }
+
+    // Bounds of every point, control points included, rounded out to font units
+    pub fn bounds(&self) -> Option<Rect> {
+        let mut points = self.contours.iter().flatten().map(|point| point.position);
+
+        let [x, y] = points.next()?;
+
+        let [x_min, y_min, x_max, y_max] = points.fold([x, y, x, y], |[x_min, y_min, x_max, y_max], [x, y]| {
+            [x_min.min(x), y_min.min(y), x_max.max(x), y_max.max(y)]
+        });
+
+        Some(Rect {
+            x_min: x_min.floor() as i16,
+            y_min: y_min.floor() as i16,
+            x_max: x_max.ceil() as i16,
+            y_max: y_max.ceil() as i16,
+        })
+    }
+
+    // Twice the signed area of the outline, positive when outer contours go counter-clockwise
+    fn signed_area(&self) -> f32 {
+        self.contours.iter().map(|contour| {
+            contour.iter().zip(contour.iter().cycle().skip(1)).map(|(a, b)| {
+                a.position[0] * b.position[1] - b.position[0] * a.position[1]
+            }).sum::<f32>()
+        }).sum()
+    }
+
+    // Push every point away from the filled area by half of `strength`, along the bisector
+    // of its neighbouring edges. The outline is then moved right by the same amount so that
+    // the left side bearing is kept and the extra width goes to the advance.
+    pub fn embolden(&mut self, strength: f32) {
+        if strength == 0. {
+            return;
+        }
+
+        let offset = strength / 2.;
+
+        // Outer contours of CFF fonts go counter-clockwise, TrueType ones go clockwise
+        let counter_clockwise = self.signed_area() > 0.;
+
+        let outward = |from: [f32; 2], to: [f32; 2]| {
+            let [dx, dy] = [to[0] - from[0], to[1] - from[1]];
+            let length = f32::sqrt(dx * dx + dy * dy);
+
+            if counter_clockwise {
+                [dy / length, -dx / length]
+            } else {
+                [-dy / length, dx / length]
+            }
+        };
+
+        for contour in &mut self.contours {
+            let positions: Vec<[f32; 2]> = contour.iter().map(|point| point.position).collect();
+            let count = positions.len();
+
+            let distinct = |a: [f32; 2], b: [f32; 2]| (a[0] - b[0]).abs() > EMBOLDEN_EPSILON || (a[1] - b[1]).abs() > EMBOLDEN_EPSILON;
+
+            for (i, point) in contour.iter_mut().enumerate() {
+                let current = positions[i];
+
+                // Neighbours are looked up around the contour, skipping repeated points
+                let previous = (1..count).map(|j| positions[(i + count - j) % count]).find(|other| distinct(*other, current));
+                let next = (1..count).map(|j| positions[(i + j) % count]).find(|other| distinct(*other, current));
+
+                let (Some(previous), Some(next)) = (previous, next) else {
+                    point.position = [current[0] + offset, current[1]];
+                    continue;
                 };
 
-                if let Some(allocation) = atlas.upload(curves_count, bytes, device, encoder, queue) {
-                    let glyph = Glyph {
-                        curves: builder.curves,
-                        allocation,
-                        bbox,
-                        descent: descent,
-                        y_offset: y_offset,
-                        left_side_bearing,
-                    };
-        
-                    glyph_cache.insert(glyph_id, glyph);
+                let normal_in = outward(previous, current);
+                let normal_out = outward(current, next);
+                let cos = normal_in[0] * normal_out[0] + normal_in[1] * normal_out[1];
+
+                // Edges going back on themselves would push the point far away
+                if 1. + cos < 1. / 16. {
+                    point.position = [current[0] + offset, current[1]];
+                    continue;
                 }
+
+                let scale = offset / (1. + cos);
+
+                point.position = [
+                    current[0] + (normal_in[0] + normal_out[0]) * scale + offset,
+                    current[1] + (normal_in[1] + normal_out[1]) * scale,
+                ];
             }
         }
     }
 
-    glyph_cache
+    // Slant the outline around the baseline
+    pub fn skew(&mut self, skew: f32) {
+        for point in self.contours.iter_mut().flatten() {
+            point.position[0] += point.position[1] * skew;
+        }
+    }
+
+    // The shader only understands quadratic curves. Approximate the cubic with a single
+    // quadratic whose control point is the average of the extrapolated cubic control points,
+    // and split the cubic in halves while the approximation error is above tolerance.
+    fn push_cubic(&mut self, p0: [f32; 2], p1: [f32; 2], p2: [f32; 2], p3: [f32; 2], depth: u32) {
+        // Upper bound of the distance between the cubic and its quadratic approximation
+        let dx = p3[0] - 3. * p2[0] + 3. * p1[0] - p0[0];
+        let dy = p3[1] - 3. * p2[1] + 3. * p1[1] - p0[1];
+        let error = f32::sqrt(3.) / 36. * f32::sqrt(dx * dx + dy * dy);
+
+        if error <= CUBIC_TOLERANCE || depth >= CUBIC_MAX_SUBDIVISIONS {
+            let control = [
+                (3. * (p1[0] + p2[0]) - p0[0] - p3[0]) / 4.,
+                (3. * (p1[1] + p2[1]) - p0[1] - p3[1]) / 4.,
+            ];
+            self.push(control, false);
+            self.push(p3, true);
+            return;
+        }
+
+        // De Casteljau subdivision at t = 0.5
+        let mid = |a: [f32; 2], b: [f32; 2]| [(a[0] + b[0]) / 2., (a[1] + b[1]) / 2.];
+        let p01 = mid(p0, p1);
+        let p12 = mid(p1, p2);
+        let p23 = mid(p2, p3);
+        let p012 = mid(p01, p12);
+        let p123 = mid(p12, p23);
+        let p0123 = mid(p012, p123);
+
+        self.push_cubic(p0, p01, p012, p0123, depth + 1);
+        self.push_cubic(p0123, p123, p23, p3, depth + 1);
+    }
 }
 
-struct BezierBuilder {
-    last_position: [f32; 2],
-    pub curves: Vec<f32>,
-    total_height: f32,
+// Push the quadratic split at its extremum along `axis`, then along x when enabled, so that
+// each part crosses any horizontal, or vertical, line at most once
+fn push_monotonic(curves: &mut Vec<f32>, curve: [[f32; 2]; 3], axis: usize) {
+    let [p0, control, p1] = curve;
+
+    let denominator = p0[axis] - 2. * control[axis] + p1[axis];
+    let t = (p0[axis] - control[axis]) / denominator;
+
+    // A quadratic has a single extremum per axis, the parts aren't split again along `axis`
+    // as rounding could find another extremum right next to their ends
+    if denominator != 0. && t > 0. && t < 1. {
+        let lerp = |a: [f32; 2], b: [f32; 2]| [a[0] + (b[0] - a[0]) * t, a[1] + (b[1] - a[1]) * t];
+
+        let mut first = lerp(p0, control);
+        let mut second = lerp(control, p1);
+        let middle = lerp(first, second);
+
+        // The tangent is flat at the extremum, rounding must not bring the extremum back
+        first[axis] = middle[axis];
+        second[axis] = middle[axis];
+
+        push_split(curves, [p0, first, middle], axis);
+        push_split(curves, [middle, second, p1], axis);
+    } else {
+        push_split(curves, curve, axis);
+    }
 }
 
-impl BezierBuilder {
-    pub fn new(total_height: f32) -> Self {
-        Self {
-            last_position: [0., 0.],
-            curves: Vec::new(),
-            total_height,
-        }
+// Push a curve monotonic along `axis`
+fn push_split(curves: &mut Vec<f32>, curve: [[f32; 2]; 3], axis: usize) {
+    if axis == 1 && SPLIT_X_EXTREMA {
+        push_monotonic(curves, curve, 0);
+        return;
     }
+
+    let [[x0, y0], [cx, cy], [x1, y1]] = curve;
+    curves.extend_from_slice(&[x0, y0, cx, cy, x1, y1, 0., 0.]);
 }
 
 impl OutlineBuilder for BezierBuilder {
     fn move_to(&mut self, x: f32, y: f32) {
-        self.last_position = [x, self.total_height - y];
+        self.close();
+        self.contours.push(vec![OutlinePoint { position: [x, y], on_curve: true }]);
     }
 
     fn line_to(&mut self, x: f32, y: f32) {
-        let [x0, y0] = self.last_position;
-        self.curves.extend_from_slice(&[x0, y0, x, self.total_height - y, x, self.total_height - y, 0., 0.]);
-        self.last_position = [x, self.total_height - y];
+        self.push([x, y], true);
     }
 
     fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
-        let [x0, y0] = self.last_position;
-        self.curves.extend_from_slice(&[x0, y0, x1, self.total_height - y1, x, self.total_height - y, 0., 0.]);
-        self.last_position = [x, self.total_height - y];
+        self.push([x1, y1], false);
+        self.push([x, y], true);
     }
 
     fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
-        self.curves.extend_from_slice(&[x1, self.total_height - y1, x2, self.total_height - y2, x, self.total_height - y, 0., 0.]);
-        self.last_position = [x, self.total_height - y];
+        self.push_cubic(self.last_position(), [x1, y1], [x2, y2], [x, y], 0);
     }
 
+    // Contours may end away from their start, close them with a line so that the winding
+    // computed by the shader stays consistent
     fn close(&mut self) {
-        
+        let Some(start) = self.contours.last().and_then(|contour| contour.first()).map(|point| point.position) else {
+            return;
+        };
+
+        if self.last_position() != start {
+            self.push(start, true);
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(curve: [[f32; 2]; 3]) -> Vec<[f32; 8]> {
+        let mut curves = Vec::new();
+        push_monotonic(&mut curves, curve, 1);
+
+        curves.chunks_exact(8).map(|curve| curve.try_into().unwrap()).collect()
+    }
+
+    #[test]
+    fn monotonic_curves_are_kept() {
+        assert_eq!(split([[0., 0.], [1., 1.], [2., 2.]]), vec![[0., 0., 1., 1., 2., 2., 0., 0.]]);
+    }
+
+    #[test]
+    fn curves_are_split_at_their_extrema() {
+        // One extremum along y, one along x
+        let curves = split([[0., 0.], [4., 2.], [0., 4.]]);
+        assert_eq!(curves.len(), 2);
+
+        let curves = split([[0., 0.], [2., 4.], [4., 0.]]);
+        assert_eq!(curves.len(), 2);
+        assert_eq!([curves[0][4], curves[0][5]], [2., 2.]);
+
+        for curve in curves {
+            // The control point is level with the extremum
+            assert_eq!(curve[3], 2.);
+        }
+    }
+
+    fn lerp(a: [f32; 2], b: [f32; 2], t: f32) -> [f32; 2] {
+        [a[0] + (b[0] - a[0]) * t, a[1] + (b[1] - a[1]) * t]
+    }
+
+    fn distance(a: [f32; 2], b: [f32; 2]) -> f32 {
+        f32::hypot(a[0] - b[0], a[1] - b[1])
+    }
+
+    #[test]
+    fn cubics_are_approximated_within_tolerance() {
+        let [p0, p1, p2, p3] = [[0., 0.], [0., 200.], [300., -100.], [300., 100.]];
+
+        let mut builder = BezierBuilder::new();
+        builder.move_to(p0[0], p0[1]);
+        builder.curve_to(p1[0], p1[1], p2[0], p2[1], p3[0], p3[1]);
+
+        let contour = &builder.contours[0];
+
+        // The S shape can't be approximated by a single quadratic
+        assert!(contour.len() > 3);
+        assert_eq!(contour[0].position, p0);
+        assert_eq!(contour[contour.len() - 1].position, p3);
+
+        let cubic: Vec<[f32; 2]> = (0..=10000).map(|i| {
+            let t = i as f32 / 10000.;
+            let [a, b, c] = [lerp(p0, p1, t), lerp(p1, p2, t), lerp(p2, p3, t)];
+
+            lerp(lerp(a, b, t), lerp(b, c, t), t)
+        }).collect();
+
+        let distance_to_cubic = |point: [f32; 2]| cubic.iter().map(|sample| distance(*sample, point)).fold(f32::MAX, f32::min);
+
+        for quadratic in contour.windows(3).step_by(2) {
+            assert!(quadratic[0].on_curve && !quadratic[1].on_curve && quadratic[2].on_curve);
+
+            let [start, control, end] = [quadratic[0].position, quadratic[1].position, quadratic[2].position];
+
+            // The quadratics start and end on the cubic, and stay close to it in between
+            assert!(distance_to_cubic(start) < 0.05);
+            assert!(distance_to_cubic(end) < 0.05);
+
+            for i in 0..=32 {
+                let t = i as f32 / 32.;
+                let point = lerp(lerp(start, control, t), lerp(control, end, t), t);
+
+                assert!(distance_to_cubic(point) <= CUBIC_TOLERANCE + 0.05);
+            }
+        }
+
+        // Every curve read by the shader is monotonic along both axes
+        for curve in builder.curves(200.).chunks_exact(8) {
+            for axis in 0..2 {
+                let (start, control, end) = (curve[axis], curve[axis + 2], curve[axis + 4]);
+
+                assert!(control >= start.min(end) - 1e-3 && control <= start.max(end) + 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn open_contours_are_closed_without_zero_length_segments() {
+        let mut builder = BezierBuilder::new();
+        builder.move_to(0., 0.);
+        builder.line_to(10., 0.);
+        builder.line_to(10., 0.);
+        builder.line_to(10., 10.);
+        builder.close();
+
+        // Closing again doesn't add another segment
+        builder.close();
+
+        // With y going down from 10
+        assert_eq!(builder.curves(10.), vec![
+            0., 10., 10., 10., 10., 10., 0., 0.,
+            10., 10., 10., 0., 10., 0., 0., 0.,
+            10., 0., 0., 10., 0., 10., 0., 0.,
+        ]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn contours_are_closed_when_the_next_one_starts() {
+        let mut builder = BezierBuilder::new();
+        builder.move_to(0., 0.);
+        builder.line_to(10., 0.);
+        builder.line_to(0., 10.);
+        builder.move_to(20., 0.);
+
+        let start = builder.contours[0].first().unwrap().position;
+        let end = builder.contours[0].last().unwrap().position;
+
+        assert_eq!(start, end);
+        assert_eq!(builder.contours[0].len(), 4);
+    }
+
+    // Clockwise square, like TrueType outer contours
+    fn square(size: f32) -> BezierBuilder {
+        let mut builder = BezierBuilder::new();
+        builder.move_to(0., 0.);
+        builder.line_to(0., size);
+        builder.line_to(size, size);
+        builder.line_to(size, 0.);
+        builder.close();
+
+        builder
+    }
+
+    #[test]
+    fn emboldening_grows_the_bounds_by_the_strength() {
+        let mut builder = square(100.);
+        builder.embolden(10.);
+
+        // Half of the strength on each side, then moved right to keep the left side bearing
+        let bounds = builder.bounds().unwrap();
+        assert_eq!([bounds.x_min, bounds.y_min, bounds.x_max, bounds.y_max], [0, -5, 110, 105]);
+
+        // The advance grows by the same width once scaled to the font size
+        let face = Face::parse(include_bytes!("../tests/fixtures/tiny.ttf"), 0).unwrap();
+        let regular = GlyphOutline::new(&face, GlyphId(1), Synthesis::default()).unwrap().bbox;
+        let bold = GlyphOutline::new(&face, GlyphId(1), Synthesis::BOLD).unwrap().bbox;
+
+        let added_width = (bold.width() - regular.width()) as f32;
+        assert_eq!(bold.x_min, regular.x_min);
+        assert!((added_width - Synthesis::BOLD.advance(face.units_per_em() as f32)).abs() <= 1.);
+    }
+
+    #[test]
+    fn skewing_shears_x_along_y() {
+        let skew = 12f32.to_radians().tan();
+        assert!((Synthesis::OBLIQUE.skew - skew).abs() < 1e-4);
+
+        let mut builder = square(100.);
+        builder.skew(skew);
+
+        let positions: Vec<[f32; 2]> = builder.contours[0].iter().map(|point| point.position).collect();
+
+        assert_eq!(positions, vec![[0., 0.], [100. * skew, 100.], [100. + 100. * skew, 100.], [100., 0.], [0., 0.]]);
+    }
+}
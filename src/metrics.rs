@@ -0,0 +1,132 @@
+use owned_ttf_parser::Face;
+use swash::{FontRef, StringId};
+
+use crate::loader;
+
+/// Vertical metrics of a face. Values are in font units as returned by `Font::metrics`,
+/// or in pixels once scaled to a font size. The y axis points up from the baseline,
+/// so the descender and the underline position are usually negative.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FontMetrics {
+    pub units_per_em: u16,
+    pub ascender: f32,
+    pub descender: f32,
+    pub line_gap: f32,
+    pub x_height: f32,
+    pub cap_height: f32,
+    pub underline_position: f32,
+    pub underline_thickness: f32,
+    pub strikeout_position: f32,
+    pub strikeout_thickness: f32,
+}
+
+impl FontMetrics {
+    pub fn new(face: &Face) -> Self {
+        let units_per_em = face.units_per_em();
+        let ascender = face.ascender() as f32;
+        let descender = face.descender() as f32;
+
+        // Old fonts miss these values in their OS/2 table, measure the glyphs instead
+        let x_height = face.x_height()
+            .map(f32::from)
+            .or_else(|| glyph_height(face, 'x'))
+            .unwrap_or(units_per_em as f32 * 0.5);
+
+        let cap_height = face.capital_height()
+            .map(f32::from)
+            .or_else(|| glyph_height(face, 'H'))
+            .unwrap_or(ascender);
+
+        let default_thickness = units_per_em as f32 / 14.;
+
+        let (underline_position, underline_thickness) = face.underline_metrics()
+            .map_or((descender / 2., default_thickness), |line| (line.position as f32, line.thickness as f32));
+
+        let (strikeout_position, strikeout_thickness) = face.strikeout_metrics()
+            .map_or((x_height / 2., default_thickness), |line| (line.position as f32, line.thickness as f32));
+
+        Self {
+            units_per_em,
+            ascender,
+            descender,
+            line_gap: face.line_gap() as f32,
+            x_height,
+            cap_height,
+            underline_position,
+            underline_thickness,
+            strikeout_position,
+            strikeout_thickness,
+        }
+    }
+
+    /// Metrics in pixels for text rendered at `size` pixels per em.
+    pub fn scale(&self, size: f32) -> Self {
+        let scale = size / self.units_per_em as f32;
+
+        Self {
+            units_per_em: self.units_per_em,
+            ascender: self.ascender * scale,
+            descender: self.descender * scale,
+            line_gap: self.line_gap * scale,
+            x_height: self.x_height * scale,
+            cap_height: self.cap_height * scale,
+            underline_position: self.underline_position * scale,
+            underline_thickness: self.underline_thickness * scale,
+            strikeout_position: self.strikeout_position * scale,
+            strikeout_thickness: self.strikeout_thickness * scale,
+        }
+    }
+
+    /// Distance between the baselines of two consecutive lines.
+    pub fn line_height(&self) -> f32 {
+        self.ascender - self.descender + self.line_gap
+    }
+}
+
+/// Names of a face from its `name` table.
+#[derive(Debug, Clone, Default)]
+pub struct FontNames {
+    pub family: Option<String>,
+    pub subfamily: Option<String>,
+    pub postscript: Option<String>,
+}
+
+impl FontNames {
+    pub fn new(font: &FontRef) -> Self {
+        Self {
+            family: loader::localized_name(font, &[StringId::TypographicFamily, StringId::Family]),
+            subfamily: loader::localized_name(font, &[StringId::TypographicSubFamily, StringId::SubFamily]),
+            postscript: loader::localized_name(font, &[StringId::PostScript]),
+        }
+    }
+}
+
+fn glyph_height(face: &Face, c: char) -> Option<f32> {
+    let glyph_id = face.glyph_index(c)?;
+
+    face.glyph_bounding_box(glyph_id).map(|bbox| bbox.y_max as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_are_read_from_the_face() {
+        let face = Face::parse(include_bytes!("../tests/fixtures/tiny.ttf"), 0).unwrap();
+        let metrics = FontMetrics::new(&face);
+
+        // hhea
+        assert_eq!([metrics.ascender, metrics.descender, metrics.line_gap], [800., -200., 0.]);
+        // OS/2
+        assert_eq!([metrics.x_height, metrics.cap_height], [350., 500.]);
+        assert_eq!([metrics.strikeout_position, metrics.strikeout_thickness], [300., 50.]);
+        // post
+        assert_eq!([metrics.underline_position, metrics.underline_thickness], [-75., 40.]);
+
+        let scaled = metrics.scale(10.);
+
+        assert_eq!([scaled.ascender, scaled.descender, scaled.underline_position], [8., -2., -0.75]);
+        assert_eq!(scaled.line_height(), 10.);
+    }
+}
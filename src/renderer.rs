@@ -1,20 +1,28 @@
 use std::mem;
 
 use bytemuck::{Pod, Zeroable};
-use owned_ttf_parser::AsFaceRef;
+use owned_ttf_parser::{colr::GradientExtend, GlyphId, RectF, Transform};
 use wgpu::{
-    util::{self, BufferInitDescriptor, DeviceExt, StagingBelt}, vertex_attr_array, BindGroup, BindGroupDescriptor, 
+    util::{self, BufferInitDescriptor, DeviceExt}, vertex_attr_array, BindGroup, BindGroupDescriptor, 
     BindGroupEntry, BindGroupLayout, BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingResource, 
     BindingType, BlendComponent, BlendFactor, BlendOperation, BlendState, Buffer, BufferBinding, 
-    BufferBindingType, BufferDescriptor, BufferSize, BufferUsages, ColorTargetState, ColorWrites, 
-    Device, FilterMode, FragmentState, FrontFace, MultisampleState, PipelineLayoutDescriptor, 
+    BufferBindingType, BufferSize, BufferUsages, ColorTargetState, ColorWrites, 
+    Device, FilterMode, Queue, FragmentState, FrontFace, MultisampleState, PipelineLayoutDescriptor, 
     PrimitiveState, PrimitiveTopology, RenderPass, RenderPipeline, RenderPipelineDescriptor, 
-    SamplerBindingType, SamplerDescriptor, ShaderModuleDescriptor, ShaderSource, ShaderStages, 
-    SurfaceConfiguration, TextureSampleType, TextureViewDimension, VertexAttribute, VertexBufferLayout, 
-    VertexFormat, VertexState, VertexStepMode
+    Sampler, SamplerBindingType, SamplerDescriptor, ShaderModuleDescriptor, ShaderSource, ShaderStages, 
+    SurfaceConfiguration, Extent3d, ImageCopyTexture, ImageDataLayout, Origin3d, Texture, TextureAspect, 
+    TextureDescriptor, TextureDimension, TextureFormat, TextureSampleType, TextureUsages, TextureView, 
+    TextureViewDescriptor, TextureViewDimension, VertexAttribute, VertexBufferLayout, VertexFormat, VertexState, 
+    VertexStepMode
 };
 
-use crate::{atlas::Atlas, ortho::orthographic_projection_matrix, typewriter::Paragraph, FontStore};
+use crate::{
+    bitmap::BitmapGlyph, color::{self, ColorLayer, Gradient, GradientKind, LayerPaint}, 
+    loader::Glyph, ortho::orthographic_projection_matrix, typewriter::{FillRule, Paragraph}, FontStore, LoadingError
+};
+
+// Gradient stops are stored in rows of this many texels, must match the shader
+const STOPS_WIDTH: u32 = 256;
 
 pub struct TextRenderer {
     pipeline: RenderPipeline,
@@ -27,12 +35,26 @@ pub struct TextRenderer {
     texture: BindGroup,
     texture_version: usize,
     texture_layout: BindGroupLayout,
-    screen_size: [u32; 2],
+    // Offset and color texels of the gradients drawn this frame
+    stops: Vec<[f32; 4]>,
+    stops_texture: Texture,
+    stops_view: TextureView,
+    // Textured quads of bitmap glyphs, drawn after the outlines
+    bitmap_pipeline: RenderPipeline,
+    bitmap_instances_buffer: Option<Buffer>,
+    bitmap_instances: Vec<BitmapInstance>,
+    bitmap_texture: BindGroup,
+    bitmap_texture_version: usize,
+    bitmap_layout: BindGroupLayout,
+    bitmap_sampler: Sampler,
+    atlas_width: u32,
 }
 
 impl TextRenderer {
-    pub fn new(device: &Device, surface_config: &SurfaceConfiguration, atlas: &Atlas) -> Self {
+    pub fn new(device: &Device, surface_config: &SurfaceConfiguration, store: &FontStore) -> Self {
+        let (atlas, bitmap_atlas) = (store.atlas(), store.bitmap_atlas());
         let screen_size = [surface_config.width, surface_config.height];
+        let atlas_width = atlas.width();
 
         let sampler = device.create_sampler(&SamplerDescriptor {
             label: Some("Text sampler"),
@@ -49,7 +71,7 @@ impl TextRenderer {
             entries: &[
                 BindGroupLayoutEntry {
                     binding: 0,
-                    visibility: ShaderStages::VERTEX,
+                    visibility: ShaderStages::VERTEX_FRAGMENT,
                     ty: BindingType::Buffer {
                         ty: BufferBindingType::Uniform,
                         has_dynamic_offset: false,
@@ -78,7 +100,8 @@ impl TextRenderer {
                     width: screen_size[0],
                     height: screen_size[1],
                 },
-                _pad: [0, 0],
+                atlas_width,
+                _pad: 0,
                 transform: orthographic_projection_matrix(0., screen_size[0] as f32, screen_size[1] as f32, 0.)
             }),
         });
@@ -116,6 +139,16 @@ impl TextRenderer {
                         multisampled: false,
                     },
                     count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Texture {
+                        sample_type: TextureSampleType::Float { filterable: false },
+                        view_dimension: TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
                 }
             ],
         });
@@ -153,14 +186,18 @@ impl TextRenderer {
                         step_mode: VertexStepMode::Instance,
                         attributes: &vertex_attr_array!(
                             1 => Float32x2,
-                            2 => Float32,
-                            3 => Float32,
-                            4 => Float32x2,
-                            5 => Float32x2,
-                            6 => Uint32,
-                            7 => Float32,
-                            8 => Sint32,
-                            9 => Float32x4
+                            2 => Float32x2,
+                            3 => Float32x3,
+                            4 => Float32x3,
+                            5 => Float32,
+                            6 => Uint32x4,
+                            7 => Float32x4,
+                            8 => Uint32,
+                            9 => Float32x3,
+                            10 => Float32x3,
+                            11 => Float32x4,
+                            12 => Float32x2,
+                            13 => Uint32x2
                         ),
                     }
                 ],
@@ -196,6 +233,102 @@ impl TextRenderer {
             multiview: None,
         });
 
+        let bitmap_sampler = device.create_sampler(&SamplerDescriptor {
+            label: Some("Text bitmap sampler"),
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            mipmap_filter: FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        let bitmap_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("Text bitmap layout"),
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::VERTEX | ShaderStages::FRAGMENT,
+                    ty: BindingType::Texture {
+                        sample_type: TextureSampleType::Float { filterable: true },
+                        view_dimension: TextureViewDimension::D2Array,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Sampler(
+                        SamplerBindingType::Filtering,
+                    ),
+                    count: None,
+                }
+            ],
+        });
+
+        let bitmap_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("text bitmap pipeline layout"),
+            bind_group_layouts: &[&constant_layout, &bitmap_layout],
+            push_constant_ranges: &[],
+        });
+
+        let bitmap_shader = device.create_shader_module(ShaderModuleDescriptor {
+            label: Some("Text bitmap shader"),
+            source: ShaderSource::Wgsl(std::borrow::Cow::Borrowed(include_str!("bitmap.wgsl"))),
+        });
+
+        let bitmap_pipeline = device.create_render_pipeline(&RenderPipelineDescriptor {
+            label: Some("Text bitmap pipeline"),
+            layout: Some(&bitmap_pipeline_layout),
+            vertex: VertexState {
+                module: &bitmap_shader,
+                entry_point: "vs_main",
+                compilation_options: Default::default(),
+                buffers: &[
+                    VertexBufferLayout {
+                        array_stride: mem::size_of::<Vertex>() as u64,
+                        step_mode: VertexStepMode::Vertex,
+                        attributes: &[VertexAttribute {
+                            shader_location: 0,
+                            format: VertexFormat::Float32x2,
+                            offset: 0,
+                        }],
+                    },
+                    VertexBufferLayout {
+                        array_stride: mem::size_of::<BitmapInstance>() as u64,
+                        step_mode: VertexStepMode::Instance,
+                        attributes: &vertex_attr_array!(
+                            1 => Float32x2,
+                            2 => Float32x2,
+                            3 => Float32x4,
+                            4 => Uint32,
+                            5 => Float32x4
+                        ),
+                    }
+                ],
+            },
+            primitive: PrimitiveState {
+                topology: PrimitiveTopology::TriangleList,
+                front_face: FrontFace::Cw,
+                ..Default::default()
+            },
+            depth_stencil: None,
+            multisample: MultisampleState { count: 1, mask: !0, alpha_to_coverage_enabled: false },
+            fragment: Some(FragmentState {
+                module: &bitmap_shader,
+                entry_point: "fs_main",
+                compilation_options: Default::default(),
+                targets: &[Some(ColorTargetState {
+                    format: surface_config.format,
+                    // Images are premultiplied
+                    blend: Some(BlendState::PREMULTIPLIED_ALPHA_BLENDING),
+                    write_mask: ColorWrites::ALL,
+                })],
+            }),
+            multiview: None,
+        });
+
+        let bitmap_texture = create_bitmap_bind_group(device, &bitmap_layout, bitmap_atlas.view(), &bitmap_sampler);
+
         let vertices = device.create_buffer_init(&util::BufferInitDescriptor {
             label: Some("Text vertex buffer"),
             contents: bytemuck::cast_slice(&VERTICES),
@@ -208,18 +341,10 @@ impl TextRenderer {
             usage: BufferUsages::INDEX,
         });
 
-        let texture = device.create_bind_group(&BindGroupDescriptor {
-            label: Some("Text texture atlas bind group"),
-            layout: &texture_layout,
-            entries:  &[
-                BindGroupEntry {
-                    binding: 0,
-                    resource: BindingResource::TextureView(
-                        atlas.view(),
-                    ),
-                },
-            ],
-        });
+        let stops_texture = create_stops_texture(device, 1);
+        let stops_view = stops_texture.create_view(&TextureViewDescriptor::default());
+
+        let texture = create_texture_bind_group(device, &texture_layout, atlas.view(), &stops_view);
 
         Self {
             pipeline,
@@ -232,60 +357,193 @@ impl TextRenderer {
             texture,
             texture_version: atlas.layer_count(),
             texture_layout,
-            screen_size,
+            stops: Vec::new(),
+            stops_texture,
+            stops_view,
+            bitmap_pipeline,
+            bitmap_instances_buffer: None,
+            bitmap_instances: Vec::new(),
+            bitmap_texture,
+            bitmap_texture_version: bitmap_atlas.layer_count(),
+            bitmap_layout,
+            bitmap_sampler,
+            atlas_width,
         }
     }
 
-    pub fn prepare(&mut self, device: &Device, paragraphs: &Vec<Paragraph>, store: &FontStore) {
+    /// Build the instances of the paragraphs to render. Glyphs that can't be uploaded to the
+    /// atlas are left out, the paragraphs are still prepared and the first error is returned.
+    pub fn prepare(&mut self, device: &Device, queue: &Queue, paragraphs: &[Paragraph], store: &mut FontStore) -> Result<(), LoadingError> {
+        store.next_frame();
+
+        let mut result = Ok(());
+
+        // Layers of every glyph by paragraph and run, `None` for glyphs without colors
+        let color_layers: Vec<Vec<Vec<Option<Vec<ColorLayer>>>>> = paragraphs.iter().map(|paragraph| {
+            paragraph.runs.iter().map(|run| {
+                store.get(run.font_key).map_or_else(Vec::new, |font| {
+                    paragraph.glyphs[run.glyphs.clone()].iter()
+                        .map(|(glyph_id, _)| font.color_layers(*glyph_id, paragraph.palette, paragraph.color))
+                        .collect()
+                })
+            }).collect()
+        }).collect();
+
+        // Glyphs outside of the cache preset are uploaded on the fly to be rendered this frame
+        paragraphs.iter().zip(&color_layers).for_each(|(paragraph, paragraph_layers)| {
+            paragraph.runs.iter().zip(paragraph_layers).for_each(|(run, run_layers)| {
+                if store.get(run.font_key).is_none() {
+                    return;
+                }
+
+                // Color glyphs are drawn with the outlines of their layers
+                let glyph_ids: Vec<GlyphId> = paragraph.glyphs[run.glyphs.clone()].iter().zip(run_layers).flat_map(|((glyph_id, _), layers)| {
+                    match layers {
+                        Some(layers) => layers.iter().map(|layer| layer.glyph_id).collect(),
+                        None => vec![*glyph_id],
+                    }
+                }).collect();
+
+                let cached = store.cache_glyphs(device, queue, run.font_key, &glyph_ids);
+
+                if result.is_ok() {
+                    result = cached;
+                }
+            });
+        });
+
         self.instances = Vec::new();
-        let mut glyph_count = 0;
+        self.bitmap_instances = Vec::new();
+        self.stops = Vec::new();
 
-        paragraphs.iter().for_each(|paragraph| {
+        paragraphs.iter().zip(&color_layers).for_each(|(paragraph, paragraph_layers)| {
 
-            let font = store.get(paragraph.font_key).expect("Paragraph has been created without valid font");
+            // Paragraphs whose font has been unloaded are skipped
+            let Some(main_font) = store.get(paragraph.font_key) else {
+                return;
+            };
 
-            let units_per_em = font.face.as_face_ref().units_per_em() as f32;
+            let size = paragraph.size as f32;
+
+            // Every run sits on the baseline of the main font
+            let baseline = paragraph.position[1] + main_font.metrics().scale(size).ascender;
 
             let mut glyph_x = paragraph.position[0];
 
-            paragraph.glyphs.iter().for_each(|(glyph_id, left)| {
-                if let Some(glyph) = font.glyph_cache.get(glyph_id) {
-                    let glyph_y = paragraph.position[0] + (glyph.y_offset as f32 / units_per_em * paragraph.size as f32) + (f32::abs(glyph.descent as f32) / units_per_em * paragraph.size as f32);
-
-                    let size = [
-                        (glyph.bbox.width() as f32 * paragraph.size as f32 / units_per_em),
-                        (glyph.bbox.height() as f32 * paragraph.size as f32 / units_per_em),
-                    ];
-
-                    let instance = Instance {
-                        _position: [glyph_x, glyph_y],
-                        _left_side_bearing: glyph.left_side_bearing as f32,
-                        _font_size: paragraph.size as f32,
-                        _size: size,
-                        _position_in_atlas: [glyph.allocation.position()[0] as f32, glyph.allocation.position()[1] as f32],
-                        _size_in_atlas: glyph.allocation.size(),
-                        _units_per_em: units_per_em,
-                        _layer: glyph.allocation.layer() as u32,
-                        _color: paragraph.color,
-                    };
-
-                    self.instances.push(instance);
-
-                    glyph_count += 1;
-                }
-                
-                glyph_x += left;
+            paragraph.runs.iter().zip(paragraph_layers).for_each(|(run, run_layers)| {
+                let glyphs = &paragraph.glyphs[run.glyphs.clone()];
+
+                let Some(font) = store.get(run.font_key) else {
+                    glyph_x += glyphs.iter().map(|(_, left)| left).sum::<f32>();
+                    return;
+                };
+
+                let metrics = font.metrics();
+                let units_per_em = metrics.units_per_em as f32;
+                let ascender = metrics.scale(size).ascender;
+
+                glyphs.iter().zip(run_layers).for_each(|((glyph_id, left), layers)| {
+                    let position = [glyph_x, baseline - ascender];
+
+                    match layers {
+                        Some(layers) => layers.iter().for_each(|layer| {
+                            let Some(glyph) = font.glyph_cache.get(&layer.glyph_id) else {
+                                return;
+                            };
+
+                            // Clip boxes are made for the regular outlines, synthetic ones go past them
+                            let clip_box = layer.clip_box.filter(|_| font.synthesis.is_none());
+
+                            let instance = match &layer.paint {
+                                LayerPaint::Solid(color) => {
+                                    Instance::new(glyph, position, paragraph.size, units_per_em, layer.transform, clip_box, *color)
+                                }
+                                LayerPaint::Gradient(gradient) => {
+                                    Instance::new(glyph, position, paragraph.size, units_per_em, layer.transform, clip_box, [1.; 4])
+                                        .and_then(|instance| instance.with_gradient(gradient, &mut self.stops))
+                                }
+                            };
+
+                            self.instances.extend(instance.map(|instance| instance.with_fill_rule(paragraph.fill_rule)));
+                        }),
+                        None => {
+                            if let Some(glyph) = font.glyph_cache.get(glyph_id) {
+                                let instance = Instance::new(glyph, position, paragraph.size, units_per_em, Transform::default(), None, paragraph.color);
+
+                                self.instances.extend(instance.map(|instance| instance.with_fill_rule(paragraph.fill_rule)));
+                            } else if let Some(glyph) = font.bitmap_cache.get(glyph_id) {
+                                self.bitmap_instances.push(BitmapInstance::new(glyph, [glyph_x, baseline], paragraph.size, paragraph.color));
+                            }
+                        }
+                    }
+
+                    glyph_x += left;
+                })
             })
         });
 
+        let stops_rows = (self.stops.len() as u32).div_ceil(STOPS_WIDTH).max(1);
+        let stops_resized = stops_rows > self.stops_texture.height();
+
+        if stops_resized {
+            self.stops_texture = create_stops_texture(device, stops_rows);
+            self.stops_view = self.stops_texture.create_view(&TextureViewDescriptor::default());
+        }
+
+        if !self.stops.is_empty() {
+            // Pad the last row so that whole rows are written
+            self.stops.resize((stops_rows * STOPS_WIDTH) as usize, [0.; 4]);
+
+            queue.write_texture(
+                ImageCopyTexture {
+                    texture: &self.stops_texture,
+                    mip_level: 0,
+                    origin: Origin3d::ZERO,
+                    aspect: TextureAspect::All,
+                },
+                bytemuck::cast_slice(&self.stops),
+                ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(STOPS_WIDTH * mem::size_of::<[f32; 4]>() as u32),
+                    rows_per_image: Some(stops_rows),
+                },
+                Extent3d {
+                    width: STOPS_WIDTH,
+                    height: stops_rows,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+
+        // The atlas texture is recreated whenever it grows, rebind it if fonts were loaded since
+        if stops_resized || self.texture_version != store.atlas().layer_count() {
+            self.texture = create_texture_bind_group(device, &self.texture_layout, store.atlas().view(), &self.stops_view);
+            self.texture_version = store.atlas().layer_count();
+        }
+
+        if self.bitmap_texture_version != store.bitmap_atlas().layer_count() {
+            self.bitmap_texture = create_bitmap_bind_group(device, &self.bitmap_layout, store.bitmap_atlas().view(), &self.bitmap_sampler);
+            self.bitmap_texture_version = store.bitmap_atlas().layer_count();
+        }
+
         self.instances_buffer = Some(device.create_buffer_init(&BufferInitDescriptor {
             label: Some("Text instances buffer"),
             usage: BufferUsages::VERTEX | BufferUsages::COPY_DST,
             contents: bytemuck::cast_slice(&self.instances[0..self.instances.len()])
         }));
+
+        self.bitmap_instances_buffer = Some(device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("Text bitmap instances buffer"),
+            usage: BufferUsages::VERTEX | BufferUsages::COPY_DST,
+            contents: bytemuck::cast_slice(&self.bitmap_instances)
+        }));
+
+        result
     }
 
     pub fn update_uniforms(&mut self, device: &Device, screen_size: [u32; 2]) {
+        let atlas_width = self.atlas_width;
+
         self.uniforms = device.create_buffer_init(&BufferInitDescriptor {
             label: Some("Text uniforms buffer"),
             usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
@@ -294,7 +552,8 @@ impl TextRenderer {
                     width: screen_size[0],
                     height: screen_size[1],
                 },
-                _pad: [0, 0],
+                atlas_width,
+                _pad: 0,
                 transform: orthographic_projection_matrix(0., screen_size[0] as f32, screen_size[1] as f32, 0.)
             }),
         });
@@ -302,23 +561,35 @@ impl TextRenderer {
 
     pub fn render<'rpass>(&'rpass mut self, render_pass: &mut RenderPass<'rpass>, screen_size: [u32; 2]) {
 
-        if self.instances.is_empty() {
-            return;
-        }
-
-        render_pass.set_pipeline(&self.pipeline);
-        render_pass.set_bind_group(0, &self.constants, &[]);
-        render_pass.set_bind_group(1, &self.texture, &[]);
-        render_pass.set_index_buffer(
-            self.indices.slice(..),
-            wgpu::IndexFormat::Uint16,
-        );
-        render_pass.set_vertex_buffer(0, self.vertices.slice(..));
-        render_pass.set_vertex_buffer(1, self.instances_buffer.as_ref().unwrap().slice(..));
-
         render_pass.set_scissor_rect(0, 0, screen_size[0], screen_size[1]);
 
-        render_pass.draw_indexed(0..INDICES.len() as u32, 0, 0..self.instances.len() as u32);
+        if !self.instances.is_empty() {
+            render_pass.set_pipeline(&self.pipeline);
+            render_pass.set_bind_group(0, &self.constants, &[]);
+            render_pass.set_bind_group(1, &self.texture, &[]);
+            render_pass.set_index_buffer(
+                self.indices.slice(..),
+                wgpu::IndexFormat::Uint16,
+            );
+            render_pass.set_vertex_buffer(0, self.vertices.slice(..));
+            render_pass.set_vertex_buffer(1, self.instances_buffer.as_ref().unwrap().slice(..));
+
+            render_pass.draw_indexed(0..INDICES.len() as u32, 0, 0..self.instances.len() as u32);
+        }
+
+        if !self.bitmap_instances.is_empty() {
+            render_pass.set_pipeline(&self.bitmap_pipeline);
+            render_pass.set_bind_group(0, &self.constants, &[]);
+            render_pass.set_bind_group(1, &self.bitmap_texture, &[]);
+            render_pass.set_index_buffer(
+                self.indices.slice(..),
+                wgpu::IndexFormat::Uint16,
+            );
+            render_pass.set_vertex_buffer(0, self.vertices.slice(..));
+            render_pass.set_vertex_buffer(1, self.bitmap_instances_buffer.as_ref().unwrap().slice(..));
+
+            render_pass.draw_indexed(0..INDICES.len() as u32, 0, 0..self.bitmap_instances.len() as u32);
+        }
     }
 }
 
@@ -333,24 +604,250 @@ pub struct Resolution {
 #[derive(Clone, Copy, Debug, PartialEq, Pod, Zeroable)]
 pub struct Params {
     screen_resolution: Resolution,
-    _pad: [u32; 2],
+    // Width of the curve atlas, which the shader reads as a single line of texels
+    atlas_width: u32,
+    _pad: u32,
     transform: [f32; 16],
 }
 
+fn create_stops_texture(device: &Device, rows: u32) -> Texture {
+    device.create_texture(&TextureDescriptor {
+        label: Some("Text gradient stops texture"),
+        size: Extent3d {
+            width: STOPS_WIDTH,
+            height: rows,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: TextureDimension::D2,
+        format: TextureFormat::Rgba32Float,
+        usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+        view_formats: &[],
+    })
+}
+
+fn create_texture_bind_group(device: &Device, layout: &BindGroupLayout, atlas: &TextureView, stops: &TextureView) -> BindGroup {
+    device.create_bind_group(&BindGroupDescriptor {
+        label: Some("Text texture atlas bind group"),
+        layout,
+        entries:  &[
+            BindGroupEntry {
+                binding: 0,
+                resource: BindingResource::TextureView(atlas),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: BindingResource::TextureView(stops),
+            },
+        ],
+    })
+}
+
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Zeroable, Pod)]
 struct Instance {
     _position: [f32; 2],
-    _left_side_bearing: f32,
+    _size: [f32; 2],
+    // Rows of the affine map from the quad to the curves in the atlas
+    _curve_x: [f32; 3],
+    _curve_y: [f32; 3],
     _font_size: f32,
+    // Position, size and layer of the curves in the atlas
+    _atlas: [u32; 4],
+    _color: [f32; 4],
+    // Paint kind in the first byte, gradient extend mode in the second, fill rule in the third
+    _paint: u32,
+    // Rows of the affine map from the quad to the gradient geometry
+    _paint_x: [f32; 3],
+    _paint_y: [f32; 3],
+    _gradient: [f32; 4],
+    _gradient_extra: [f32; 2],
+    // First texel and number of gradient stops
+    _stops: [u32; 2],
+}
+
+impl Instance {
+    // Place a glyph whose origin is at `position`, in pixels. The outline is transformed by
+    // `transform` and clipped by `clip_box`, both in font units. None if nothing is visible.
+    fn new(
+        glyph: &Glyph,
+        position: [f32; 2],
+        font_size: u16,
+        units_per_em: f32,
+        transform: Transform,
+        clip_box: Option<RectF>,
+        color: [f32; 4],
+    ) -> Option<Self> {
+        let scale = font_size as f32 / units_per_em;
+        let bbox = glyph.bbox;
+
+        // Bounds of the transformed outline
+        let mut rect = [
+            (bbox.x_min, bbox.y_min),
+            (bbox.x_max, bbox.y_min),
+            (bbox.x_max, bbox.y_max),
+            (bbox.x_min, bbox.y_max),
+        ].iter().fold(RectF { x_min: f32::MAX, y_min: f32::MAX, x_max: f32::MIN, y_max: f32::MIN }, |rect, (x, y)| {
+            let (x, y) = (*x as f32, *y as f32);
+            let (x, y) = (transform.a * x + transform.c * y + transform.e, transform.b * x + transform.d * y + transform.f);
+
+            RectF {
+                x_min: rect.x_min.min(x),
+                y_min: rect.y_min.min(y),
+                x_max: rect.x_max.max(x),
+                y_max: rect.y_max.max(y),
+            }
+        });
+
+        if let Some(clip_box) = clip_box {
+            rect = RectF {
+                x_min: rect.x_min.max(clip_box.x_min),
+                y_min: rect.y_min.max(clip_box.y_min),
+                x_max: rect.x_max.min(clip_box.x_max),
+                y_max: rect.y_max.min(clip_box.y_max),
+            };
+        }
+
+        if rect.x_min >= rect.x_max || rect.y_min >= rect.y_max {
+            return None;
+        }
+
+        let (width, height) = (rect.x_max - rect.x_min, rect.y_max - rect.y_min);
+
+        // The quad goes down from the top left corner while font units go up, and the
+        // curves are stored flipped below the top of the bounding box
+        let quad_to_font = Transform::new(width, 0., 0., -height, rect.x_min, rect.y_max);
+        let font_to_curve = Transform::new(1., 0., 0., -1., 0., bbox.y_max as f32);
+        let quad_to_curve = Transform::combine(font_to_curve, Transform::combine(color::invert(transform)?, quad_to_font));
+
+        let top = glyph.y_offset as f32 + f32::abs(glyph.descent as f32) + bbox.y_max as f32 - rect.y_max;
+
+        Some(Self {
+            _position: [position[0] + rect.x_min * scale, position[1] + top * scale],
+            _size: [width * scale, height * scale],
+            _curve_x: [quad_to_curve.a, quad_to_curve.c, quad_to_curve.e],
+            _curve_y: [quad_to_curve.b, quad_to_curve.d, quad_to_curve.f],
+            _font_size: font_size as f32,
+            _atlas: [
+                glyph.allocation.position()[0],
+                glyph.allocation.position()[1],
+                glyph.allocation.size(),
+                glyph.allocation.layer() as u32,
+            ],
+            _color: color,
+            _paint: 0,
+            _paint_x: [quad_to_font.a, quad_to_font.c, quad_to_font.e],
+            _paint_y: [quad_to_font.b, quad_to_font.d, quad_to_font.f],
+            _gradient: [0.; 4],
+            _gradient_extra: [0.; 2],
+            _stops: [0, 0],
+        })
+    }
+
+    fn with_fill_rule(mut self, fill_rule: FillRule) -> Self {
+        let fill_rule = match fill_rule {
+            FillRule::NonZero => 0,
+            FillRule::EvenOdd => 1,
+        };
+
+        self._paint = (self._paint & !0xff0000) | fill_rule << 16;
+        self
+    }
+
+    // Fill with a gradient whose stops are appended to `stops`
+    fn with_gradient(mut self, gradient: &Gradient, stops: &mut Vec<[f32; 4]>) -> Option<Self> {
+        if gradient.stops.is_empty() {
+            return None;
+        }
+
+        // Before this the paint map goes from the quad to font units
+        let quad_to_font = Transform::new(
+            self._paint_x[0], self._paint_y[0], self._paint_x[1], self._paint_y[1], self._paint_x[2], self._paint_y[2],
+        );
+        let quad_to_paint = Transform::combine(color::invert(gradient.transform)?, quad_to_font);
+
+        self._paint_x = [quad_to_paint.a, quad_to_paint.c, quad_to_paint.e];
+        self._paint_y = [quad_to_paint.b, quad_to_paint.d, quad_to_paint.f];
+
+        let (kind, geometry, extra) = match gradient.kind {
+            GradientKind::Linear { start, end } => (1, [start[0], start[1], end[0], end[1]], [0.; 2]),
+            GradientKind::Radial { start_center, start_radius, end_center, end_radius } => {
+                (2, [start_center[0], start_center[1], start_radius, end_center[0]], [end_center[1], end_radius])
+            }
+            GradientKind::Sweep { center, start_angle, end_angle } => (3, [center[0], center[1], start_angle, end_angle], [0.; 2]),
+        };
+
+        let extend = match gradient.extend {
+            GradientExtend::Pad => 0,
+            GradientExtend::Repeat => 1,
+            GradientExtend::Reflect => 2,
+        };
+
+        self._paint = (self._paint & !0xffff) | kind | extend << 8;
+        self._gradient = geometry;
+        self._gradient_extra = extra;
+        self._stops = [stops.len() as u32, gradient.stops.len() as u32];
+
+        gradient.stops.iter().for_each(|(offset, color)| {
+            stops.push([*offset, 0., 0., 0.]);
+            stops.push(*color);
+        });
+
+        Some(self)
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Zeroable, Pod)]
+struct BitmapInstance {
+    _position: [f32; 2],
     _size: [f32; 2],
-    _position_in_atlas: [f32; 2],
-    _size_in_atlas: u32,
-    _units_per_em: f32,
+    // Area of the image in the atlas, in texels
+    _atlas_rect: [f32; 4],
     _layer: u32,
     _color: [f32; 4],
 }
 
+impl BitmapInstance {
+    // Place a bitmap glyph whose origin is at `baseline`, in pixels. The image of the strike
+    // is scaled to the font size, color images only take the alpha of `color`.
+    fn new(glyph: &BitmapGlyph, baseline: [f32; 2], font_size: u16, color: [f32; 4]) -> Self {
+        let scale = font_size as f32 / glyph.pixels_per_em as f32;
+
+        let [x, y] = glyph.allocation.position();
+        let [width, height] = glyph.allocation.size();
+
+        Self {
+            _position: [
+                baseline[0] + glyph.x as f32 * scale,
+                baseline[1] - (glyph.y as f32 + glyph.height as f32) * scale,
+            ],
+            _size: [glyph.width as f32 * scale, glyph.height as f32 * scale],
+            _atlas_rect: [x as f32, y as f32, width as f32, height as f32],
+            _layer: glyph.allocation.layer() as u32,
+            _color: if glyph.mask { color } else { [1., 1., 1., color[3]] },
+        }
+    }
+}
+
+fn create_bitmap_bind_group(device: &Device, layout: &BindGroupLayout, atlas: &TextureView, sampler: &Sampler) -> BindGroup {
+    device.create_bind_group(&BindGroupDescriptor {
+        label: Some("Text bitmap atlas bind group"),
+        layout,
+        entries:  &[
+            BindGroupEntry {
+                binding: 0,
+                resource: BindingResource::TextureView(atlas),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: BindingResource::Sampler(sampler),
+            },
+        ],
+    })
+}
+
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Pod, Zeroable)]
 struct Vertex {
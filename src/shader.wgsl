@@ -1,47 +1,71 @@
 struct Params {
     screen_resolution: vec2<f32>,
-    _pad: vec2<f32>,
+    atlas_width: u32,
+    _pad: u32,
     transform: mat4x4<f32>,
 }
 
 struct VertexInput {
     @location(0) v_pos: vec2<f32>,
     @location(1) pos: vec2<f32>,
-    @location(2) left_side_bearing: f32,
-    @location(3) font_size: f32,
-    @location(4) size: vec2<f32>,
-    @location(5) atlas_pos: vec2<f32>,
-    @location(6) atlas_size: u32,
-    @location(7) units_per_em: f32,
-    @location(8) layer: i32,
-    @location(9) color: vec4<f32>,
+    @location(2) size: vec2<f32>,
+    @location(3) curve_x: vec3<f32>,
+    @location(4) curve_y: vec3<f32>,
+    @location(5) font_size: f32,
+    @location(6) atlas: vec4<u32>,
+    @location(7) color: vec4<f32>,
+    @location(8) paint: u32,
+    @location(9) paint_x: vec3<f32>,
+    @location(10) paint_y: vec3<f32>,
+    @location(11) gradient: vec4<f32>,
+    @location(12) gradient_extra: vec2<f32>,
+    @location(13) stops: vec2<u32>,
 }
 
 struct VertexOutput {
     @builtin(position) position: vec4<f32>,
     @location(0) uv: vec2<f32>,
-    @location(1) pos: vec2<f32>,
-    @location(2) left_side_bearing: f32,
-    @location(3) font_size: f32,
-    @location(4) size: vec2<f32>,
-    @location(5) atlas_pos: vec2<f32>,
-    @location(6) atlas_size: i32,
-    @location(7) units_per_em: f32,
-    @location(8) layer: f32,
-    @location(9) color: vec4<f32>,
+    @location(1) paint_uv: vec2<f32>,
+    @location(2) font_size: f32,
+    @location(3) @interpolate(flat) atlas_pos: vec2<i32>,
+    @location(4) @interpolate(flat) atlas_size: i32,
+    @location(5) @interpolate(flat) layer: i32,
+    @location(6) color: vec4<f32>,
+    @location(7) @interpolate(flat) paint: u32,
+    @location(8) gradient: vec4<f32>,
+    @location(9) gradient_extra: vec2<f32>,
+    @location(10) @interpolate(flat) stops: vec2<u32>,
 }
 
+// Paint kinds, the gradient extend mode is stored in the second byte
+const PAINT_SOLID: u32 = 0u;
+const PAINT_LINEAR: u32 = 1u;
+const PAINT_RADIAL: u32 = 2u;
+const PAINT_SWEEP: u32 = 3u;
+
+const EXTEND_PAD: u32 = 0u;
+const EXTEND_REPEAT: u32 = 1u;
+const EXTEND_REFLECT: u32 = 2u;
+
+// Fill rules, stored in the third byte of the paint
+const FILL_NONZERO: u32 = 0u;
+const FILL_EVEN_ODD: u32 = 1u;
+
+// Gradient stops take two texels, the offset then the color
+const STOPS_WIDTH: u32 = 256u;
+
+// Texels before the band table of a glyph
+const BAND_HEADER: i32 = 8;
+
 @group(0) @binding(0) var<uniform> params: Params;
 @group(0) @binding(1) var atlas_sampler: sampler;
 @group(1) @binding(0) var atlas_texture: texture_2d_array<f32>;
+@group(1) @binding(1) var stops_texture: texture_2d<f32>;
 
 @vertex
 fn vs_main(input: VertexInput) -> VertexOutput {
     var output: VertexOutput;
 
-    output.uv = vec2<f32>(input.v_pos);
-    output.layer = f32(input.layer);
-
     var transform = mat4x4<f32>(
         vec4<f32>(input.size.x, 0.,           0., 0.),
         vec4<f32>(0.,           input.size.y, 0., 0.),
@@ -50,183 +74,275 @@ fn vs_main(input: VertexInput) -> VertexOutput {
     );
 
     output.position = params.transform * transform * vec4<f32>(input.v_pos * 1., 0., 1.);
-    output.pos = input.pos;
+
+    // Affine maps from the quad to the curves and to the paint, exact under linear interpolation
+    let quad = vec3<f32>(input.v_pos, 1.);
+    output.uv = vec2<f32>(dot(input.curve_x, quad), dot(input.curve_y, quad));
+    output.paint_uv = vec2<f32>(dot(input.paint_x, quad), dot(input.paint_y, quad));
+
     output.font_size = input.font_size;
-    output.size = input.size;
-    output.layer = f32(input.layer);
-    output.atlas_size = i32(input.atlas_size);
-    output.atlas_pos = input.atlas_pos;
-    output.left_side_bearing = input.left_side_bearing;
-    output.units_per_em = input.units_per_em;
+    output.atlas_pos = vec2<i32>(input.atlas.xy);
+    output.atlas_size = i32(input.atlas.z);
+    output.layer = i32(input.atlas.w);
     output.color = input.color;
+    output.paint = input.paint;
+    output.gradient = input.gradient;
+    output.gradient_extra = input.gradient_extra;
+    output.stops = input.stops;
 
     return output;
 }
 
-fn test_cross(a: vec2<f32>, b: vec2<f32>, p: vec2<f32>) -> f32 {
-    return sign((b.y - a.y) * (p.x - a.x) - (b.x - a.x) * (p.y - a.y));
-}
+// Winding of the curve around the origin along the ray going right, with points relative to
+// the pixel. Crossings further than half a pixel count fully, closer ones are weighted by
+// their distance so that edges are antialiased. Which roots cross the ray is found from the
+// side of the ray each point lies on, so that curves ending on the ray are counted once.
+fn ray_winding(p0: vec2<f32>, p1: vec2<f32>, p2: vec2<f32>, pixels_per_unit: f32) -> f32 {
+    let shift = select(0u, 2u, p0.y > 0.) + select(0u, 4u, p1.y > 0.) + select(0u, 8u, p2.y > 0.);
+    let code = (0x2e74u >> shift) & 3u;
+
+    if code == 0u {
+        return 0.;
+    }
+
+    // Roots of a t^2 - 2 b t + p0 = 0, lines have their control point on their end point
+    let a = p0 - p1 * 2. + p2;
+    let b = p0 - p1;
+    let d = sqrt(max(b.y * b.y - a.y * p0.y, 0.));
+
+    // Avoid the cancellation of b and d, nearly straight curves would lose their root to
+    // rounding. The root dividing by a is never selected when a vanishes.
+    var t1: f32;
+    var t2: f32;
+
+    if b.y >= 0. {
+        let q = b.y + d;
+        t1 = p0.y / q;
+        t2 = q / a.y;
+    } else {
+        let q = b.y - d;
+        t1 = q / a.y;
+        t2 = p0.y / q;
+    }
 
-fn sign_bezier(A: vec2<f32>, B: vec2<f32>, C: vec2<f32>, p: vec2<f32>) -> f32 {
-    let a: vec2<f32> = C - A;
-    let b: vec2<f32> = B - A;
-    let c: vec2<f32> = p - A;
+    let x1 = (a.x * t1 - b.x * 2.) * t1 + p0.x;
+    let x2 = (a.x * t2 - b.x * 2.) * t2 + p0.x;
 
-    let r: f32 = (a.x * b.y - b.x * a.y);
+    var winding = 0.;
 
-    if abs(r) < 0.001 {
-        return test_cross(A, B, p);
+    if (code & 1u) != 0u {
+        winding += saturate(x1 * pixels_per_unit + .5);
     }
 
-    let bary = vec2<f32>(c.x * b.y - b.x * c.y, a.x * c.y - c.x * a.y) / r;
-    let d = vec2<f32>(bary.y * .5, 0.) + 1. - bary.x - bary.y;
-    return mix(
-        sign(d.x * d.x - d.y), 
-        mix(
-            -1.,
-            1.,
-            step(
-                test_cross(A, B, p) * test_cross(B, C, p),
-                0.
-            )
-        ),
-        step(
-            (d.x - d.y),
-            0.
-        )
-    ) * test_cross(A, C, B);
-}
+    if code > 1u {
+        winding -= saturate(x2 * pixels_per_unit + .5);
+    }
 
-fn remap(value: f32, from1: f32, to1: f32, from2: f32, to2: f32) -> f32 {
-    return (value - from1) / (to1 - from1) * (to2 - from2) + from2;
+    return winding;
 }
 
-fn solve_cubic(a: f32, b: f32, c: f32) -> vec3<f32> {
-    var p = b - a * a / 3.;
-    var p3 = p * p * p;
-    var q = a * (2. * a * a - 9. * b) / 27. + c;
-    var d = q * q + 4. * p3 / 27.;
-    var offset = -a / 3.;
-
-    if d >= 0. {
-        var z = sqrt(d);
-        var x = (vec2(z, -z) -q) / 2.;
-        var uv = sign(x) * pow(abs(x), vec2(1./3.));
-        return vec3<f32>(offset + uv.x + uv.y);
+// Coverage of a pixel from its winding number. The winding direction differs between
+// TrueType and CFF outlines, so only its magnitude is used.
+fn fill(winding: f32, fill_rule: u32) -> f32 {
+    if fill_rule == FILL_EVEN_ODD {
+        return 1. - abs(1. - fract(winding * .5) * 2.);
     }
 
-    var v = acos(-sqrt(-27. / p3) * q / 2.) / 3.;
-    var m = cos(v);
-    var n = sin(v) * 1.732050808;
-    return vec3<f32>(m + m, -n - m, n - m) * sqrt(-p / 3.) + offset;
+    return min(abs(winding), 1.);
 }
 
-fn sd_bezier(A: vec2<f32>, B: vec2<f32>, C: vec2<f32>, p: vec2<f32>) -> f32 {
-    var new_B = mix(B + vec2<f32>(1e-4), B, abs(sign(B * 2. - A - C)));
-    var a = new_B - A;
-    var b = A - new_B * 2. + C;
-    var c = a * 2.;
-    var d = A - p;
-    var k = vec3<f32>(3. * dot(a, b), 2. * dot(a, a) + dot(d, b), dot(d, a)) / dot(b, b);
-    var t = clamp(solve_cubic(k.x, k.y, k.z), vec3<f32>(0.), vec3<f32>(1.));
-    var pos = A + (c + b * t.x) * t.x;
-    var dis = length(pos - p);
-    pos = A + (c + b * t.y) * t.y;
-    dis = min(dis, length(pos - p));
-    pos = A + (c + b * t.z) * t.z;
-    dis = min(dis, length(pos- p));
-    return dis * sign_bezier(A, B, C, p);
-}
+// Glyph data is stored row after row from the position of the glyph in the atlas
+fn load_texel(glyph: vec2<i32>, layer: i32, offset: i32) -> f32 {
+    let width = i32(params.atlas_width);
+    let index = glyph.y * width + glyph.x + offset;
 
-fn sdf_triplet_alpha(sdf: vec3<f32>, horz_scale: f32, vert_scale: f32, vgrad: f32, doffset: f32) -> vec3<f32> {
-    let hdoffset = mix(doffset * horz_scale, doffset * vert_scale, vgrad);
-    let rdoffset = mix(doffset, hdoffset, 0.);
-    var alpha = smoothstep(vec3(.5 - rdoffset), vec3(.5 + rdoffset), sdf);
-    alpha = pow(alpha, vec3(1. + .2 * vgrad * 0.));
-    return alpha;
+    return textureLoad(atlas_texture, vec2<i32>(index % width, index / width), layer, 0).x;
 }
 
-@fragment
-fn fs_main(input: VertexOutput) -> @location(0) vec4<f32> {
-    let font_size = input.font_size;
-
-    var uv = input.uv;
-
-    uv.x = remap(uv.x, 0., 1., 0., input.size.x * input.units_per_em / font_size);
-    uv.x += input.left_side_bearing;
-    uv.y = remap(uv.y, 0., 1., 0., input.size.y * input.units_per_em / font_size);
-
-    var curve_points_count = input.atlas_size;
-    var sideR = 0.;
-    var sideG = 0.;
-    var sideB = 0.;
-
-    var distR = 0.;
-    var distG = 0.;
-    var distB = 0.;
-
-    {
-        var y_offset = input.atlas_pos.y;
-        var x_offset = input.atlas_pos.x;
-        var i = 0;
-        loop {
-            if !(i < curve_points_count) {
-                break;
-            }
+// Points of the curve at `offset`, relative to p
+fn load_curve(glyph: vec2<i32>, layer: i32, offset: i32, p: vec2<f32>) -> array<vec2<f32>, 3> {
+    return array<vec2<f32>, 3>(
+        vec2<f32>(load_texel(glyph, layer, offset), load_texel(glyph, layer, offset + 1)) - p,
+        vec2<f32>(load_texel(glyph, layer, offset + 2), load_texel(glyph, layer, offset + 3)) - p,
+        vec2<f32>(load_texel(glyph, layer, offset + 4), load_texel(glyph, layer, offset + 5)) - p,
+    );
+}
 
-            var atlas_x_offset = x_offset + f32(i);
+fn load_stop(index: u32) -> vec4<f32> {
+    return textureLoad(stops_texture, vec2<i32>(i32(index % STOPS_WIDTH), i32(index / STOPS_WIDTH)), 0);
+}
 
-            var pixels_before_eol = 2048. - atlas_x_offset;
+// Position along the gradient, returns a negative value where the radial gradient isn't defined
+fn gradient_position(input: VertexOutput) -> f32 {
+    let p = input.paint_uv;
+    let g = input.gradient;
 
-            if pixels_before_eol == 0. {
-                y_offset = y_offset + 1.;
-                x_offset = 0.;
-                curve_points_count = curve_points_count - i;
+    switch input.paint & 0xffu {
+        case PAINT_LINEAR: {
+            let d = g.zw - g.xy;
+            return dot(p - g.xy, d) / max(dot(d, d), 1e-6);
+        }
+        case PAINT_RADIAL: {
+            // Two point conical gradient, find the largest t where p is on the circle of radius r(t)
+            let c0 = g.xy;
+            let r0 = g.z;
+            let cd = vec2<f32>(g.w, input.gradient_extra.x) - c0;
+            let dr = input.gradient_extra.y - r0;
+            let pd = p - c0;
+
+            let a = dot(cd, cd) - dr * dr;
+            let b = dot(pd, cd) + r0 * dr;
+            let c = dot(pd, pd) - r0 * r0;
+
+            if abs(a) < 1e-6 {
+                let t = c / (2. * b);
+                if b == 0. || r0 + t * dr < 0. {
+                    return -1e30;
+                }
+                return t;
             }
 
-            let ax = textureSample(atlas_texture, atlas_sampler, vec2<f32>(atlas_x_offset / 2048., y_offset / 2048.), i32(0)).x;
-            let ay = textureSample(atlas_texture, atlas_sampler, vec2<f32>((atlas_x_offset + 1.) / 2048., y_offset / 2048.), i32(0)).x;
-            let az = textureSample(atlas_texture, atlas_sampler, vec2<f32>((atlas_x_offset + 2.) / 2048., y_offset / 2048.), i32(0)).x;
-            let aw = textureSample(atlas_texture, atlas_sampler, vec2<f32>((atlas_x_offset + 3.) / 2048., y_offset / 2048.), i32(0)).x;
-            let bx = textureSample(atlas_texture, atlas_sampler, vec2<f32>((atlas_x_offset + 4.) / 2048., y_offset / 2048.), i32(0)).x;
-            let by = textureSample(atlas_texture, atlas_sampler, vec2<f32>((atlas_x_offset + 5.) / 2048., y_offset / 2048.), i32(0)).x;
-
-            if ((uv.y > ay && uv.y < by) || (uv.y > by && uv.y < ay)) {
-                let snR = sign_bezier(vec2<f32>(ax, ay), vec2<f32>(az, aw), vec2<f32>(bx, by), uv - vec2(1./3., 0.));
-                let snG = sign_bezier(vec2<f32>(ax, ay), vec2<f32>(az, aw), vec2<f32>(bx, by), uv);
-                let snB = sign_bezier(vec2<f32>(ax, ay), vec2<f32>(az, aw), vec2<f32>(bx, by), uv + vec2(1./3., 0.));
-                sideR += snR;
-                sideG += snG;
-                sideB += snB;
+            let discriminant = b * b - a * c;
+            if discriminant < 0. {
+                return -1e30;
             }
 
-            let x = abs(sd_bezier(vec2<f32>(ax, ay), vec2<f32>(az, aw), vec2<f32>(bx, by), uv));
-            if distG == 0. || x < distG {
-                distR = abs(sd_bezier(vec2<f32>(ax, ay), vec2<f32>(az, aw), vec2<f32>(bx, by), uv - vec2(1./3., 0.)));
-                distG = x;
-                distB = abs(sd_bezier(vec2<f32>(ax, ay), vec2<f32>(az, aw), vec2<f32>(bx, by), uv + vec2(1./3., 0.)));
+            let t1 = (b + sqrt(discriminant)) / a;
+            let t2 = (b - sqrt(discriminant)) / a;
+            let t = max(t1, t2);
+            if r0 + t * dr >= 0. {
+                return t;
             }
-
-            continuing {
-                if pixels_before_eol == 0. {
-                    i = 0;
-                } else {
-                    i = i + 8;
-                }
+            let other = min(t1, t2);
+            if r0 + other * dr >= 0. {
+                return other;
             }
+            return -1e30;
+        }
+        case PAINT_SWEEP: {
+            let d = p - g.xy;
+            var angle = atan2(d.y, d.x);
+            if angle < 0. {
+                angle += 6.283185307;
+            }
+            return (angle - g.z) / (g.w - g.z);
+        }
+        default: {
+            return 0.;
         }
     }
+}
 
-    var vgrad = abs(dpdy(distG));
+fn paint_color(input: VertexOutput) -> vec4<f32> {
+    if (input.paint & 0xffu) == PAINT_SOLID {
+        return input.color;
+    }
 
-    let horz_scale = .5;
-    let vert_scale = .6;
+    var t = gradient_position(input);
+    if t == -1e30 {
+        return vec4<f32>(0.);
+    }
 
-    var triplet_alpha = sdf_triplet_alpha(vec3(distR, distG, distB), horz_scale, vert_scale, vgrad, 26. - 0.16 * font_size);
+    switch (input.paint >> 8u) & 0xffu {
+        case EXTEND_REPEAT: {
+            t = fract(t);
+        }
+        case EXTEND_REFLECT: {
+            t = 1. - abs(fract(t * .5) * 2. - 1.);
+        }
+        default: {
+            t = clamp(t, 0., 1.);
+        }
+    }
+
+    let first = input.stops.x;
+    let count = input.stops.y;
+
+    var previous_offset = load_stop(first).x;
+    var previous_color = load_stop(first + 1u);
 
-    if sideG == -2. {
-        triplet_alpha.r = 1. - triplet_alpha.r;
+    if t <= previous_offset {
+        return previous_color;
     }
 
-    return vec4(input.color.rgb, 1 - triplet_alpha.r);
-}
\ No newline at end of file
+    for (var i = 1u; i < count; i++) {
+        let offset = load_stop(first + i * 2u).x;
+        let color = load_stop(first + i * 2u + 1u);
+
+        if t <= offset {
+            return mix(previous_color, color, (t - previous_offset) / max(offset - previous_offset, 1e-6));
+        }
+
+        previous_offset = offset;
+        previous_color = color;
+    }
+
+    return previous_color;
+}
+
+@fragment
+fn fs_main(input: VertexOutput) -> @location(0) vec4<f32> {
+    let uv = input.uv;
+    let pixels_per_unit = 1. / max(fwidth(uv), vec2<f32>(1e-6));
+
+    let glyph = input.atlas_pos;
+
+    // Header of the glyph, see `bands.rs`
+    let band_counts = vec2<i32>(i32(load_texel(glyph, input.layer, 1)), i32(load_texel(glyph, input.layer, 0)));
+    let band_origin = vec2<f32>(load_texel(glyph, input.layer, 2), load_texel(glyph, input.layer, 3));
+    let bands_per_unit = vec2<f32>(load_texel(glyph, input.layer, 4), load_texel(glyph, input.layer, 5));
+
+    // Samples outside of the glyph use the closest bands, no curve of the other bands can cross their rays
+    let band = clamp(vec2<i32>(floor((uv - band_origin) * bands_per_unit)), vec2<i32>(0), band_counts - 1);
+
+    // Winding numbers along a horizontal and a vertical ray
+    var horizontal = 0.;
+    var vertical = 0.;
+
+    let horizontal_band = BAND_HEADER + band.y * 2;
+    let horizontal_list = i32(load_texel(glyph, input.layer, horizontal_band));
+    let horizontal_count = i32(load_texel(glyph, input.layer, horizontal_band + 1));
+
+    for (var i = 0; i < horizontal_count; i++) {
+        let curve = load_curve(glyph, input.layer, i32(load_texel(glyph, input.layer, horizontal_list + i)), uv);
+
+        // Curves are sorted by their right end, the remaining ones are all left of the pixel
+        if max(max(curve[0].x, curve[1].x), curve[2].x) * pixels_per_unit.x < -.5 {
+            break;
+        }
+
+        horizontal += ray_winding(curve[0], curve[1], curve[2], pixels_per_unit.x);
+    }
+
+    let vertical_band = BAND_HEADER + (band_counts.y + band.x) * 2;
+    let vertical_list = i32(load_texel(glyph, input.layer, vertical_band));
+    let vertical_count = i32(load_texel(glyph, input.layer, vertical_band + 1));
+
+    for (var i = 0; i < vertical_count; i++) {
+        let curve = load_curve(glyph, input.layer, i32(load_texel(glyph, input.layer, vertical_list + i)), uv);
+
+        if max(max(curve[0].y, curve[1].y), curve[2].y) * pixels_per_unit.y < -.5 {
+            break;
+        }
+
+        // Swapping the axes mirrors the curve, which flips its winding
+        vertical -= ray_winding(curve[0].yx, curve[1].yx, curve[2].yx, pixels_per_unit.y);
+    }
+
+    let fill_rule = (input.paint >> 16u) & 0xffu;
+    let horizontal_coverage = fill(horizontal, fill_rule);
+    let vertical_coverage = fill(vertical, fill_rule);
+
+    // Each ray is exact away from the edges it crosses, favor the one closest to an edge
+    let horizontal_weight = 1. - abs(horizontal_coverage * 2. - 1.);
+    let vertical_weight = 1. - abs(vertical_coverage * 2. - 1.);
+
+    var coverage = (horizontal_coverage + vertical_coverage) * .5;
+    if horizontal_weight + vertical_weight > 1e-4 {
+        coverage = (horizontal_coverage * horizontal_weight + vertical_coverage * vertical_weight) / (horizontal_weight + vertical_weight);
+    }
+
+    let color = paint_color(input);
+
+    return vec4(color.rgb, color.a * coverage);
+}
@@ -1,20 +1,88 @@
+use owned_ttf_parser::GlyphId;
 use swash::CacheKey;
 use wgpu::{CommandEncoderDescriptor, SurfaceConfiguration};
 
-use std::collections::HashMap;
+use std::{collections::{BTreeSet, HashMap}, path::{Path, PathBuf}, sync::Arc};
 
-use crate::{atlas::Atlas, loader::Font, LoadingError};
+use crate::{
+    atlas::{bitmap::BitmapAtlas, Atlas, AtlasBudget, AtlasConfig}, bitmap::{BitmapGlyph, GlyphBitmap}, database::FontHandle,
+    loader::{self, FaceInfo, Font, Glyph, GlyphOutline, PreparedFont, Synthesis, Variation}, metrics::{FontMetrics, FontNames}, LoadingError
+};
 
 pub struct FontStore {
     cache: HashMap<CacheKey, Font>,
     atlas: Atlas,
+    bitmap_atlas: BitmapAtlas,
+    fallbacks: Vec<CacheKey>,
+    disk_cache: Option<PathBuf>,
+    frame: u64,
+    outline_usage: Usage,
+    bitmap_usage: Usage,
+}
+
+// Glyphs of every font ordered by the frame they were last used in, so that the least
+// recently used one is found without going through every glyph cache
+#[derive(Default)]
+struct Usage(BTreeSet<(u64, CacheKey, GlyphId)>);
+
+impl Usage {
+    fn insert(&mut self, last_used: u64, font_key: CacheKey, glyph_id: GlyphId) {
+        self.0.insert((last_used, font_key, glyph_id));
+    }
+
+    fn remove(&mut self, last_used: u64, font_key: CacheKey, glyph_id: GlyphId) {
+        self.0.remove(&(last_used, font_key, glyph_id));
+    }
+
+    fn touch(&mut self, last_used: &mut u64, frame: u64, font_key: CacheKey, glyph_id: GlyphId) {
+        self.remove(*last_used, font_key, glyph_id);
+        *last_used = frame;
+        self.insert(frame, font_key, glyph_id);
+    }
+
+    // Remove the glyph that has been unused for the longest time from the cache returned by
+    // `glyphs`, as long as it wasn't used in `frame`. Returns false when there is nothing
+    // left to evict.
+    fn evict<G>(
+        &mut self,
+        frame: u64,
+        fonts: &mut HashMap<CacheKey, Font>,
+        glyphs: impl FnOnce(&mut Font) -> &mut HashMap<GlyphId, G>,
+        deallocate: impl FnOnce(G)
+    ) -> bool {
+        let Some(&(last_used, font_key, glyph_id)) = self.0.first().filter(|(last_used, _, _)| *last_used < frame) else {
+            return false;
+        };
+
+        self.remove(last_used, font_key, glyph_id);
+
+        if let Some(glyph) = fonts.get_mut(&font_key).and_then(|font| glyphs(font).remove(&glyph_id)) {
+            deallocate(glyph);
+        }
+
+        true
+    }
 }
 
 impl FontStore {
-    pub fn new(device: &wgpu::Device, surface_config: &SurfaceConfiguration) -> Self {
+    pub fn new(device: &wgpu::Device, _surface_config: &SurfaceConfiguration) -> Self {
+        Self::with_atlas_config(device, AtlasConfig::default())
+    }
+
+    /// Create a store whose atlases have the dimensions of `atlas_config`, reduced to
+    /// what `device` supports.
+    pub fn with_atlas_config(device: &wgpu::Device, atlas_config: AtlasConfig) -> Self {
+        let atlas_config = atlas_config.fit(&device.limits());
+
         Self {
             cache: HashMap::new(),
-            atlas: Atlas::new(device, surface_config),  
+            atlas: Atlas::new(device, atlas_config),
+            bitmap_atlas: BitmapAtlas::new(device, atlas_config),
+            fallbacks: Vec::new(),
+            disk_cache: None,
+            frame: 0,
+            outline_usage: Usage::default(),
+            bitmap_usage: Usage::default(),
         }
     }
 
@@ -25,25 +93,406 @@ impl FontStore {
         font_file_path: &str,
         cache_preset: &str
     ) -> Result<CacheKey, LoadingError>{
+        self.load_face(device, queue, font_file_path, 0, cache_preset)
+    }
+
+    /// Load the face at `index` of a font collection (`.ttc`/`.otc`).
+    /// Every face loaded gets its own `CacheKey`.
+    pub fn load_face(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        font_file_path: &str,
+        index: usize,
+        cache_preset: &str
+    ) -> Result<CacheKey, LoadingError>{
+        let data = std::fs::read(font_file_path)?;
+
+        self.load_face_from_bytes(device, queue, data, index, cache_preset)
+    }
+
+    /// Load a face found in a `FontDatabase`.
+    pub fn load_handle(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        handle: &FontHandle,
+        cache_preset: &str
+    ) -> Result<CacheKey, LoadingError>{
+        let data = std::fs::read(&handle.path)?;
+
+        self.load_face_from_bytes(device, queue, data, handle.index, cache_preset)
+    }
+
+    /// Load a font already held in memory, e.g. embedded with `include_bytes!`
+    /// or downloaded at runtime. Accepts `Vec<u8>`, `Arc<[u8]>` and `&'static [u8]`.
+    pub fn load_from_bytes(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        data: impl Into<Arc<[u8]>>,
+        cache_preset: &str
+    ) -> Result<CacheKey, LoadingError>{
+        self.load_face_from_bytes(device, queue, data, 0, cache_preset)
+    }
+
+    pub fn load_face_from_bytes(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        data: impl Into<Arc<[u8]>>,
+        index: usize,
+        cache_preset: &str
+    ) -> Result<CacheKey, LoadingError>{
+        let font = Font::new(data, index, &[], Synthesis::default())?;
+
+        self.insert(device, queue, font, cache_preset)
+    }
+
+    /// Create an instance of a loaded variable font with the given axis values, such as
+    /// weight (`wght`), width (`wdth`), optical size (`opsz`) or slant (`slnt`).
+    /// The instance gets its own key and glyph cache.
+    pub fn create_instance(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        font_key: CacheKey,
+        variations: &[Variation],
+        cache_preset: &str
+    ) -> Result<CacheKey, LoadingError> {
+        let font = self.cache.get(&font_key).ok_or(LoadingError::UnknownFont)?.instance(variations)?;
+
+        self.insert(device, queue, font, cache_preset)
+    }
+
+    /// Create a synthetic bold or oblique variant of a loaded font, for families that
+    /// don't ship these faces. The variant gets its own key and glyph cache, and text
+    /// shaped with it gets the wider advances of emboldened glyphs.
+    pub fn create_synthetic(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        font_key: CacheKey,
+        synthesis: Synthesis,
+        cache_preset: &str
+    ) -> Result<CacheKey, LoadingError> {
+        let font = self.cache.get(&font_key).ok_or(LoadingError::UnknownFont)?.synthetic(synthesis)?;
+
+        self.insert(device, queue, font, cache_preset)
+    }
+
+    // Extract the preset glyphs of a parsed font, upload them and add the font to the store
+    fn insert(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        font: Font,
+        cache_preset: &str
+    ) -> Result<CacheKey, LoadingError> {
+        let prepared = PreparedFont::new(font, cache_preset, self.disk_cache.as_deref());
+
+        self.commit(device, queue, prepared)
+    }
+
+    /// Upload a font prepared on another thread and add it to the store. Parsing and
+    /// outline extraction already happened in `PreparedFont`, only the atlas is written here.
+    /// When the atlas budget is reached, glyphs unused in the current frame are evicted to
+    /// make room for the preset.
+    pub fn commit(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        prepared: PreparedFont
+    ) -> Result<CacheKey, LoadingError> {
+        let PreparedFont { mut font, outlines } = prepared;
 
         let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
 
-        let font = Font::from_file(device, &mut encoder, queue, font_file_path, 0, cache_preset, &mut self.atlas)?;
+        self.upload_outline_with_eviction(|atlas| font.upload_outlines(device, &mut encoder, &outlines, atlas))?;
 
         queue.submit(Some(encoder.finish()));
 
-        let cache_key = font.key.clone();
+        let cache_key = font.key;
+
+        for (glyph_id, glyph) in &font.glyph_cache {
+            self.outline_usage.insert(glyph.last_used, cache_key, *glyph_id);
+        }
 
         self.cache.insert(cache_key, font);
 
         Ok(cache_key)
     }
 
+    /// Keep the outlines of cache presets in `dir`, so that the next runs load fonts without
+    /// extracting them again. Files are keyed by font data, instance and preset, `None`
+    /// disables the cache.
+    pub fn set_disk_cache(&mut self, dir: Option<PathBuf>) {
+        self.disk_cache = dir;
+    }
+
+    pub fn disk_cache(&self) -> Option<&Path> {
+        self.disk_cache.as_deref()
+    }
+
+    /// Remove a font from the store and give the atlas space of its glyphs back.
+    /// Paragraphs shaped with this font are no longer rendered, returns false if
+    /// the font wasn't loaded.
+    pub fn unload(&mut self, font_key: CacheKey) -> bool {
+        let Some(font) = self.cache.remove(&font_key) else {
+            return false;
+        };
+
+        self.fallbacks.retain(|fallback| *fallback != font_key);
+
+        for (glyph_id, glyph) in font.glyph_cache {
+            self.outline_usage.remove(glyph.last_used, font_key, glyph_id);
+            self.atlas.deallocate(glyph.allocation);
+        }
+
+        for (glyph_id, glyph) in font.bitmap_cache {
+            self.bitmap_usage.remove(glyph.last_used, font_key, glyph_id);
+            self.bitmap_atlas.deallocate(glyph.allocation);
+        }
+
+        true
+    }
+
+    /// Fonts tried in order for the characters missing from the font a text is shaped with.
+    pub fn set_fallbacks(&mut self, fallbacks: &[CacheKey]) {
+        self.fallbacks = fallbacks.to_vec();
+    }
+
+    pub fn fallbacks(&self) -> &[CacheKey] {
+        &self.fallbacks
+    }
+
+    pub fn contains(&self, font_key: CacheKey) -> bool {
+        self.cache.contains_key(&font_key)
+    }
+
+    /// Upload the glyphs of `font_key` that aren't cached yet, so text using characters
+    /// outside of the cache preset still renders. Does nothing if every glyph is cached.
+    /// Glyphs without outlines fall back to their embedded image, if the font has one.
+    ///
+    /// Glyphs are marked as used in the current frame. When the atlas budget is reached,
+    /// the least recently used glyphs of previous frames are evicted to make room.
+    /// Glyphs that still don't fit are skipped and the first error is returned once the
+    /// other glyphs are uploaded.
+    pub fn cache_glyphs(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        font_key: CacheKey,
+        glyph_ids: &[GlyphId]
+    ) -> Result<(), LoadingError> {
+        let frame = self.frame;
+
+        let font = self.cache.get_mut(&font_key).ok_or(LoadingError::UnknownFont)?;
+
+        for glyph_id in glyph_ids {
+            if let Some(glyph) = font.glyph_cache.get_mut(glyph_id) {
+                self.outline_usage.touch(&mut glyph.last_used, frame, font_key, *glyph_id);
+            }
+
+            if let Some(glyph) = font.bitmap_cache.get_mut(glyph_id) {
+                self.bitmap_usage.touch(&mut glyph.last_used, frame, font_key, *glyph_id);
+            }
+        }
+
+        if !glyph_ids.iter().any(|glyph_id| font.needs_caching(*glyph_id)) {
+            return Ok(());
+        }
+
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+        let mut result = Ok(());
+
+        for glyph_id in glyph_ids {
+            let Some(font) = self.cache.get(&font_key).filter(|font| font.needs_caching(*glyph_id)) else {
+                continue;
+            };
+
+            let uploaded = match font.outline_glyph(*glyph_id) {
+                Some(outline) => self.cache_outline(device, &mut encoder, font_key, *glyph_id, outline),
+                None => match font.bitmap_glyph(*glyph_id) {
+                    Some(bitmap) => self.cache_bitmap(device, &mut encoder, font_key, *glyph_id, bitmap),
+                    None => Ok(()),
+                },
+            };
+
+            if result.is_ok() {
+                result = uploaded;
+            }
+        }
+
+        queue.submit(Some(encoder.finish()));
+
+        result
+    }
+
+    fn cache_outline(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        font_key: CacheKey,
+        glyph_id: GlyphId,
+        outline: GlyphOutline
+    ) -> Result<(), LoadingError> {
+        let allocation = self.upload_outline_with_eviction(|atlas| outline.upload(device, encoder, atlas))?;
+
+        if let Some(font) = self.cache.get_mut(&font_key) {
+            font.glyph_cache.insert(glyph_id, Glyph::new(outline, allocation, self.frame));
+            self.outline_usage.insert(self.frame, font_key, glyph_id);
+        }
+
+        Ok(())
+    }
+
+    fn cache_bitmap(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        font_key: CacheKey,
+        glyph_id: GlyphId,
+        bitmap: GlyphBitmap
+    ) -> Result<(), LoadingError> {
+        let allocation = self.upload_bitmap_with_eviction(|atlas| bitmap.upload(device, encoder, atlas))?;
+
+        if let Some(font) = self.cache.get_mut(&font_key) {
+            font.bitmap_cache.insert(glyph_id, BitmapGlyph::new(bitmap, allocation, self.frame));
+            self.bitmap_usage.insert(self.frame, font_key, glyph_id);
+        }
+
+        Ok(())
+    }
+
+    /// Limit the memory used by the glyph atlas, `None` lets it grow without bound.
+    /// The budget applies to the outline atlas and the bitmap atlas separately.
+    pub fn set_atlas_budget(&mut self, budget: Option<AtlasBudget>) {
+        self.atlas.set_budget(budget);
+        self.bitmap_atlas.set_budget(budget);
+    }
+
+    // Glyphs used since this point are protected from eviction
+    pub(crate) fn next_frame(&mut self) {
+        self.frame += 1;
+    }
+
+    // Run `upload` until it succeeds, evicting the least recently used glyph each time the
+    // atlas budget is reached. Other errors can't be fixed by evicting and are returned.
+    fn upload_outline_with_eviction<T>(
+        &mut self,
+        mut upload: impl FnMut(&mut Atlas) -> Result<T, LoadingError>
+    ) -> Result<T, LoadingError> {
+        loop {
+            match upload(&mut self.atlas) {
+                Err(LoadingError::AtlasExhausted) if self.evict_least_recently_used() => {}
+                result => return result,
+            }
+        }
+    }
+
+    // Same as `upload_outline_with_eviction` for the images of bitmap glyphs
+    fn upload_bitmap_with_eviction<T>(
+        &mut self,
+        mut upload: impl FnMut(&mut BitmapAtlas) -> Result<T, LoadingError>
+    ) -> Result<T, LoadingError> {
+        loop {
+            match upload(&mut self.bitmap_atlas) {
+                Err(LoadingError::AtlasExhausted) if self.evict_least_recently_used_bitmap() => {}
+                result => return result,
+            }
+        }
+    }
+
+    // Drop the glyph that has been unused for the longest time, as long as it wasn't
+    // used in the current frame. Returns false when there is nothing left to evict.
+    fn evict_least_recently_used(&mut self) -> bool {
+        self.outline_usage.evict(self.frame, &mut self.cache, |font| &mut font.glyph_cache, |glyph| {
+            self.atlas.deallocate(glyph.allocation)
+        })
+    }
+
+    // Same as `evict_least_recently_used` for the images of bitmap glyphs
+    fn evict_least_recently_used_bitmap(&mut self) -> bool {
+        self.bitmap_usage.evict(self.frame, &mut self.cache, |font| &mut font.bitmap_cache, |glyph| {
+            self.bitmap_atlas.deallocate(glyph.allocation)
+        })
+    }
+
+    /// List the faces contained in a font file, to pick an index for `load_face`.
+    pub fn faces(font_file_path: &str) -> Result<Vec<FaceInfo>, LoadingError> {
+        let data = std::fs::read(font_file_path)?;
+
+        loader::faces(&data)
+    }
+
+    pub fn faces_from_bytes(data: &[u8]) -> Result<Vec<FaceInfo>, LoadingError> {
+        loader::faces(data)
+    }
+
     pub fn atlas(&self) -> &Atlas {
         &self.atlas
     }
 
+    pub fn bitmap_atlas(&self) -> &BitmapAtlas {
+        &self.bitmap_atlas
+    }
+
+    /// Vertical metrics of a loaded font in pixels, for text rendered at `size` pixels per em.
+    pub fn metrics(&self, font_key: CacheKey, size: f32) -> Option<FontMetrics> {
+        self.cache.get(&font_key).map(|font| font.metrics().scale(size))
+    }
+
+    /// Family, subfamily and PostScript names of a loaded font.
+    pub fn names(&self, font_key: CacheKey) -> Option<FontNames> {
+        self.cache.get(&font_key).map(Font::names)
+    }
+
     pub fn get(&self, font_key: CacheKey) -> Option<&Font> {
         self.cache.get(&font_key)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use owned_ttf_parser::Rect;
+
+    use crate::atlas::{allocation::Allocation, allocator::Allocator};
+
+    use super::*;
+
+    #[test]
+    fn least_recently_used_glyphs_are_evicted_first() {
+        let mut font = Font::new(&include_bytes!("../tests/fixtures/tiny.ttf")[..], 0, &[], Synthesis::default()).unwrap();
+        let font_key = font.key;
+        let mut allocator = Allocator::new(16);
+        let mut usage = Usage::default();
+
+        // Glyph n was last used in frame n
+        for id in 1..=3 {
+            let outline = GlyphOutline {
+                curves: Vec::new(),
+                bbox: Rect { x_min: 0, y_min: 0, x_max: 1, y_max: 1 },
+                descent: 0,
+                y_offset: 0,
+                left_side_bearing: 0,
+            };
+            let allocation = Allocation { layer: 0, region: allocator.allocate(4).unwrap() };
+
+            font.glyph_cache.insert(GlyphId(id), Glyph::new(outline, allocation, id as u64));
+            usage.insert(id as u64, font_key, GlyphId(id));
+        }
+
+        let glyph = font.glyph_cache.get_mut(&GlyphId(1)).unwrap();
+        usage.touch(&mut glyph.last_used, 5, font_key, GlyphId(1));
+
+        let mut fonts = HashMap::from([(font_key, font)]);
+        let mut evicted = Vec::new();
+
+        while usage.evict(5, &mut fonts, |font| &mut font.glyph_cache, |glyph| evicted.push(glyph.last_used)) {}
+
+        // The glyph used in the current frame is kept
+        assert_eq!(evicted, vec![2, 3]);
+        assert!(fonts[&font_key].glyph_cache.contains_key(&GlyphId(1)));
+    }
+}
@@ -1,34 +1,88 @@
-use owned_ttf_parser::{AsFaceRef, GlyphId};
-use swash::{shape::ShapeContext, text::Script, CacheKey};
+use std::{iter, ops::Range};
 
-use crate::{loader::Glyph, FontStore};
+use owned_ttf_parser::GlyphId;
+use swash::{shape::ShapeContext, text::{Category, Codepoint, Script}, CacheKey};
+
+use crate::{loader::{Font, Synthesis}, FontStore};
 
 pub struct Paragraph {
     pub glyphs: Vec<(GlyphId, f32)>,
+    /// Fonts of the glyphs, in order. Text shaped with fallback fonts has one run per font change.
+    pub runs: Vec<Run>,
     pub position: [f32; 2],
     pub width: f32,
     pub size: u16,
     pub font_key: CacheKey,
     pub color: [f32; 4],
+    /// Palette used for the layers of color glyphs
+    pub palette: u16,
+    pub fill_rule: FillRule,
+}
+
+/// How the winding number of a point decides whether it is inside the outline.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum FillRule {
+    /// Inside where contours wind around the point, so overlapping contours stay filled.
+    /// This is the rule of TrueType and CFF outlines.
+    #[default]
+    NonZero,
+    /// Inside where the point is surrounded by an odd number of contours
+    EvenOdd,
+}
+
+/// Consecutive glyphs of a paragraph that come from the same font.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Run {
+    pub font_key: CacheKey,
+    /// Indices of the glyphs in `Paragraph::glyphs`
+    pub glyphs: Range<usize>,
 }
 
 impl Paragraph {
     pub fn new(position: [f32; 2], size: u16, color: [f32; 4], font_key: CacheKey) -> Self {
         Self {
             glyphs: Vec::new(),
+            runs: Vec::new(),
             position,
             width: 0.,
             size,
             font_key,
             color,
+            palette: 0,
+            fill_rule: FillRule::NonZero,
         }
     }
 
+    pub fn set_palette(&mut self, palette: u16) {
+        self.palette = palette;
+    }
+
+    pub fn set_fill_rule(&mut self, fill_rule: FillRule) {
+        self.fill_rule = fill_rule;
+    }
+
     pub fn append(&mut self, glyph_id: GlyphId, left: f32) {
+        self.append_from(self.font_key, glyph_id, left);
+    }
+
+    /// Append a glyph of another font than the main font of the paragraph.
+    pub fn append_from(&mut self, font_key: CacheKey, glyph_id: GlyphId, left: f32) {
+        match self.runs.last_mut() {
+            Some(run) if run.font_key == font_key => run.glyphs.end += 1,
+            _ => self.runs.push(Run {
+                font_key,
+                glyphs: self.glyphs.len()..self.glyphs.len() + 1,
+            }),
+        }
+
         self.width += left;
         self.glyphs.push((glyph_id, left));
     }
 
+    /// A paragraph is invalidated once one of its fonts is unloaded from the store.
+    pub fn is_valid(&self, font_store: &FontStore) -> bool {
+        font_store.contains(self.font_key) && self.runs.iter().all(|run| font_store.contains(run.font_key))
+    }
 
 }
 
@@ -36,6 +90,12 @@ pub struct TypeWriter {
     context: ShapeContext,
 }
 
+impl Default for TypeWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl TypeWriter {
 
     pub fn new() -> Self {
@@ -44,26 +104,146 @@ impl TypeWriter {
         }
     }
 
+    /// Shape `text` with `font_key`, characters it doesn't have are taken from the
+    /// fallback fonts of the store.
     pub fn shape_text(&mut self, font_store: &FontStore, font_key: CacheKey, position: [f32; 2], size: u16, color: [f32; 4], text: &str) -> Option<Paragraph> {
-        if let Some(font) = font_store.get(font_key) {
+        let fonts: Vec<CacheKey> = iter::once(font_key)
+            .chain(font_store.fallbacks().iter().copied().filter(|fallback| *fallback != font_key))
+            .collect();
+
+        self.shape_text_with_fallbacks(font_store, &fonts, position, size, color, text)
+    }
+
+    /// Shape `text` with a chain of fonts, e.g. Roboto then Noto Sans CJK then Noto Emoji.
+    /// The text is split into runs shaped with the first font that supports their characters,
+    /// the first font is the main font of the paragraph and takes characters no font supports.
+    pub fn shape_text_with_fallbacks(&mut self, font_store: &FontStore, fonts: &[CacheKey], position: [f32; 2], size: u16, color: [f32; 4], text: &str) -> Option<Paragraph> {
+        let main_font = *fonts.first()?;
+
+        font_store.get(main_font)?;
+
+        let fonts: Vec<(CacheKey, &Font)> = fonts.iter()
+            .filter_map(|font_key| font_store.get(*font_key).map(|font| (*font_key, font)))
+            .collect();
+
+        let mut paragraph = Paragraph::new(position, size, color, main_font);
+
+        for (font_key, range) in coverage_runs(main_font, &fonts, text) {
+            let Some(font) = font_store.get(font_key) else {
+                continue;
+            };
+
+            let text = &text[range];
+
+            // Scripts are resolved per run, characters shared between scripts don't decide it
+            let script = text.chars()
+                .map(|character| character.script())
+                .find(|script| !matches!(script, Script::Common | Script::Inherited | Script::Unknown))
+                .unwrap_or(Script::Latin);
+
             let mut shaper = self.context.builder(font.as_ref())
-                .script(Script::Latin)
+                .script(script)
                 .size(size as f32)
+                .variations(font.variation_settings())
                 .build();
 
-            let face = font.face.as_face_ref();
-
-            let mut paragraph = Paragraph::new(position, size, color, font_key);
-
             shaper.add_str(text);
             shaper.shape_with(|cluster| {
-                let glyph_id = GlyphId(cluster.glyphs[0].id);
-                paragraph.append(glyph_id, cluster.glyphs[0].advance)
+                for glyph in cluster.glyphs {
+                    let advance = glyph_advance(glyph.advance, font.synthesis, size as f32);
+
+                    paragraph.append_from(font_key, GlyphId(glyph.id), advance);
+                }
             });
+        }
+
+        Some(paragraph)
+    }
+}
+
+// Emboldened glyphs are wider, marks and other zero width glyphs stay in place
+fn glyph_advance(advance: f32, synthesis: Synthesis, size: f32) -> f32 {
+    if advance > 0. {
+        advance + synthesis.advance(size)
+    } else {
+        advance
+    }
+}
+
+// Split the text into runs of characters supported by the same font, the first font of the
+// chain that has a glyph for them. Marks, joiners and spaces stay in the run they follow.
+fn coverage_runs(main_font: CacheKey, fonts: &[(CacheKey, &Font)], text: &str) -> Vec<(CacheKey, Range<usize>)> {
+    let mut runs: Vec<(CacheKey, Range<usize>)> = Vec::new();
 
-            Some(paragraph)
-        } else {
-            None
+    let supports = |font: &Font, character: char| font.face().glyph_index(character).is_some();
+
+    for (index, character) in text.char_indices() {
+        let end = index + character.len_utf8();
+
+        let attached = matches!(
+            character.category(),
+            Category::Mark | Category::SpacingMark | Category::EnclosingMark | Category::NonspacingMark | Category::Format
+        );
+
+        let current = runs.last().and_then(|(font_key, _)| fonts.iter().find(|(key, _)| key == font_key));
+
+        let font_key = match current {
+            Some((font_key, _)) if attached => *font_key,
+            Some((font_key, font)) if character.is_whitespace() && supports(font, character) => *font_key,
+            _ => fonts.iter()
+                .find(|(_, font)| supports(font, character))
+                .map_or(main_font, |(font_key, _)| *font_key),
+        };
+
+        match runs.last_mut() {
+            Some((run_font, range)) if *run_font == font_key => range.end = end,
+            _ => runs.push((font_key, index..end)),
         }
-    } 
-}
\ No newline at end of file
+    }
+
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn font(data: &'static [u8]) -> Font {
+        Font::new(data, 0, &[], Synthesis::default()).unwrap()
+    }
+
+    #[test]
+    fn text_is_split_by_the_first_font_covering_it() {
+        // The primary font covers A, the fallback covers B
+        let primary = font(include_bytes!("../tests/fixtures/tiny.ttf"));
+        let fallback = font(include_bytes!("../tests/fixtures/tiny-fallback.ttf"));
+        let fonts = [(primary.key, &primary), (fallback.key, &fallback)];
+
+        let runs = coverage_runs(primary.key, &fonts, "AAB\u{301}BA");
+
+        // The mark stays with the base character it follows
+        assert_eq!(runs, vec![(primary.key, 0..2), (fallback.key, 2..6), (primary.key, 6..7)]);
+    }
+
+    #[test]
+    fn characters_no_font_covers_go_to_the_main_font() {
+        let primary = font(include_bytes!("../tests/fixtures/tiny.ttf"));
+        let fallback = font(include_bytes!("../tests/fixtures/tiny-fallback.ttf"));
+        let fonts = [(primary.key, &primary), (fallback.key, &fallback)];
+
+        // Neither font has Z or a space
+        let runs = coverage_runs(primary.key, &fonts, "B ZA");
+
+        assert_eq!(runs, vec![(fallback.key, 0..1), (primary.key, 1..4)]);
+    }
+
+    #[test]
+    fn emboldened_glyphs_advance_by_the_added_width() {
+        // 1/24 of the em at 24 pixels per em
+        assert_eq!(glyph_advance(14.4, Synthesis::BOLD, 24.), 15.4);
+        assert_eq!(glyph_advance(14.4, Synthesis::OBLIQUE, 24.), 14.4);
+
+        // Marks don't move
+        assert_eq!(glyph_advance(0., Synthesis::BOLD, 24.), 0.);
+    }
+}
@@ -0,0 +1,276 @@
+mod woff2;
+
+use std::sync::Arc;
+
+use miniz_oxide::inflate::decompress_to_vec_zlib_with_limit;
+use owned_ttf_parser::FaceParsingError;
+
+use crate::LoadingError;
+
+const WOFF_SIGNATURE: [u8; 4] = *b"wOFF";
+const WOFF2_SIGNATURE: [u8; 4] = *b"wOF2";
+
+const WOFF_HEADER_SIZE: usize = 44;
+
+// Web fonts decompressing to more than this are rejected, so that a small file can't
+// make us allocate without bound
+const MAX_SFNT_SIZE: usize = 256 << 20;
+
+// The search range of the sfnt header is 16 times the largest power of two below the table
+// count and has to fit in 16 bits
+const MAX_TABLES: usize = 4095;
+
+/// Decompress WOFF and WOFF2 fonts to the sfnt data read by swash and ttf-parser.
+/// Any other data is returned as is.
+pub(crate) fn decode(data: Arc<[u8]>) -> Result<Arc<[u8]>, LoadingError> {
+    let sfnt = match data.get(..4) {
+        Some(signature) if signature == WOFF_SIGNATURE => decode_woff(&data),
+        Some(signature) if signature == WOFF2_SIGNATURE => woff2::decode(&data),
+        _ => return Ok(data),
+    };
+
+    sfnt.map(Arc::from).ok_or(LoadingError::Parse(FaceParsingError::MalformedFont))
+}
+
+// WOFF 1.0 compresses every table on its own with zlib, tables that don't shrink are stored as is
+fn decode_woff(data: &[u8]) -> Option<Vec<u8>> {
+    let mut header = Stream::new(data);
+
+    header.skip(4)?;
+    let flavor = header.u32()?;
+    header.skip(4)?;
+    let table_count = header.u16()? as usize;
+
+    if table_count > MAX_TABLES {
+        return None;
+    }
+
+    let mut directory = Stream::new(data.get(WOFF_HEADER_SIZE..)?);
+    let mut tables = Vec::with_capacity(table_count);
+    let mut sfnt_size = 0usize;
+
+    for _ in 0..table_count {
+        let tag = directory.tag()?;
+        let offset = directory.u32()? as usize;
+        let compressed_length = directory.u32()? as usize;
+        let length = directory.u32()? as usize;
+        directory.skip(4)?;
+
+        sfnt_size = sfnt_size.checked_add(length)?;
+
+        if sfnt_size > MAX_SFNT_SIZE || compressed_length > length {
+            return None;
+        }
+
+        let compressed = data.get(offset..offset.checked_add(compressed_length)?)?;
+
+        let table = if compressed_length < length {
+            decompress_to_vec_zlib_with_limit(compressed, length).ok()?
+        } else {
+            compressed.to_vec()
+        };
+
+        if table.len() != length {
+            return None;
+        }
+
+        tables.push(Table { tag, data: table });
+    }
+
+    Some(write_sfnt(&[(flavor, (0..tables.len()).collect())], &tables))
+}
+
+struct Table {
+    tag: [u8; 4],
+    data: Vec<u8>,
+}
+
+// Assemble a font, or a collection when there are several, from its tables. Each font is
+// given by its flavor and the indices of its tables, tables can be shared between fonts.
+fn write_sfnt(fonts: &[(u32, Vec<usize>)], tables: &[Table]) -> Vec<u8> {
+    let collection = fonts.len() > 1;
+
+    let collection_header_size = if collection { 12 + fonts.len() * 4 } else { 0 };
+    let directories_size: usize = fonts.iter().map(|(_, indices)| 12 + indices.len() * 16).sum();
+
+    // Table data follows every directory, each table aligned on 4 bytes
+    let mut offsets = Vec::with_capacity(tables.len());
+    let mut offset = collection_header_size + directories_size;
+
+    for table in tables {
+        offsets.push(offset as u32);
+        offset += table.data.len().next_multiple_of(4);
+    }
+
+    let mut sfnt = Vec::with_capacity(offset);
+
+    if collection {
+        sfnt.extend_from_slice(b"ttcf");
+        sfnt.extend_from_slice(&0x00010000u32.to_be_bytes());
+        sfnt.extend_from_slice(&(fonts.len() as u32).to_be_bytes());
+
+        let mut directory_offset = collection_header_size;
+
+        for (_, indices) in fonts {
+            sfnt.extend_from_slice(&(directory_offset as u32).to_be_bytes());
+            directory_offset += 12 + indices.len() * 16;
+        }
+    }
+
+    for (flavor, indices) in fonts {
+        // Fonts are checked to have at most `MAX_TABLES` tables, the header fields fit in 16 bits
+        let table_count = indices.len() as u32;
+        let entry_selector = table_count.checked_ilog2().unwrap_or(0);
+        let search_range = (1 << entry_selector) * 16;
+
+        sfnt.extend_from_slice(&flavor.to_be_bytes());
+        sfnt.extend_from_slice(&(table_count as u16).to_be_bytes());
+        sfnt.extend_from_slice(&(search_range as u16).to_be_bytes());
+        sfnt.extend_from_slice(&(entry_selector as u16).to_be_bytes());
+        sfnt.extend_from_slice(&((table_count * 16 - search_range) as u16).to_be_bytes());
+
+        // Table records are sorted by tag
+        let mut indices = indices.clone();
+        indices.sort_by_key(|index| tables[*index].tag);
+
+        for index in indices {
+            let table = &tables[index];
+
+            sfnt.extend_from_slice(&table.tag);
+            sfnt.extend_from_slice(&checksum(&table.data).to_be_bytes());
+            sfnt.extend_from_slice(&offsets[index].to_be_bytes());
+            sfnt.extend_from_slice(&(table.data.len() as u32).to_be_bytes());
+        }
+    }
+
+    for table in tables {
+        sfnt.extend_from_slice(&table.data);
+        sfnt.resize(sfnt.len().next_multiple_of(4), 0);
+    }
+
+    sfnt
+}
+
+fn checksum(data: &[u8]) -> u32 {
+    data.chunks(4).fold(0u32, |sum, chunk| {
+        let mut word = [0; 4];
+        word[..chunk.len()].copy_from_slice(chunk);
+
+        sum.wrapping_add(u32::from_be_bytes(word))
+    })
+}
+
+// Big endian reader, every read returns None past the end of the data
+struct Stream<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Stream<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, offset: 0 }
+    }
+
+    fn bytes(&mut self, count: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.offset..self.offset.checked_add(count)?)?;
+        self.offset += count;
+
+        Some(bytes)
+    }
+
+    fn skip(&mut self, count: usize) -> Option<()> {
+        self.bytes(count).map(|_| ())
+    }
+
+    fn array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        self.bytes(N)?.try_into().ok()
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.array().map(u8::from_be_bytes)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.array().map(u16::from_be_bytes)
+    }
+
+    fn i16(&mut self) -> Option<i16> {
+        self.array().map(i16::from_be_bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.array().map(u32::from_be_bytes)
+    }
+
+    fn tag(&mut self) -> Option<[u8; 4]> {
+        self.array()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTF: &[u8] = include_bytes!("../tests/fixtures/tiny.ttf");
+    const WOFF: &[u8] = include_bytes!("../tests/fixtures/tiny.woff");
+    const WOFF2: &[u8] = include_bytes!("../tests/fixtures/tiny.woff2");
+
+    #[test]
+    fn sfnt_is_returned_as_is() {
+        assert_eq!(&*decode(Arc::from(TTF)).unwrap(), TTF);
+    }
+
+    #[test]
+    fn decode_woff() {
+        assert_eq!(&*decode(Arc::from(WOFF)).unwrap(), TTF);
+    }
+
+    #[test]
+    fn decode_woff2() {
+        assert_eq!(&*decode(Arc::from(WOFF2)).unwrap(), TTF);
+    }
+
+    #[test]
+    fn decoded_font_parses() {
+        let data = decode(Arc::from(WOFF2)).unwrap();
+        let face = owned_ttf_parser::Face::parse(&data, 0).unwrap();
+
+        assert_eq!(face.glyph_index('A').map(|glyph| glyph.0), Some(1));
+    }
+
+    #[test]
+    fn truncated_files_are_rejected() {
+        for data in [WOFF, WOFF2] {
+            for length in [8, 40, data.len() / 2, data.len() - 8] {
+                assert!(decode(Arc::from(&data[..length])).is_err());
+            }
+        }
+    }
+
+    // Empty tables, the header fields of the sfnt can't describe that many
+    fn too_many_tables(signature: [u8; 4], header_size: usize, entry: &[u8]) -> Arc<[u8]> {
+        let mut data = signature.to_vec();
+
+        data.extend_from_slice(&0x00010000u32.to_be_bytes());
+        data.extend_from_slice(&0u32.to_be_bytes());
+        data.extend_from_slice(&(MAX_TABLES as u16 + 1).to_be_bytes());
+        data.resize(header_size, 0);
+
+        for _ in 0..=MAX_TABLES {
+            data.extend_from_slice(entry);
+        }
+
+        data.resize(data.len() + 64, 0);
+        Arc::from(data)
+    }
+
+    #[test]
+    fn too_many_tables_are_rejected() {
+        let mut woff_entry = b"name".to_vec();
+        woff_entry.resize(20, 0);
+
+        assert!(decode(too_many_tables(WOFF_SIGNATURE, WOFF_HEADER_SIZE, &woff_entry)).is_err());
+        // Known name tag, original length of 0
+        assert!(decode(too_many_tables(WOFF2_SIGNATURE, 48, &[5, 0])).is_err());
+    }
+}
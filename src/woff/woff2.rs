@@ -0,0 +1,501 @@
+use std::{collections::HashMap, io::Read};
+
+use super::{write_sfnt, Stream, Table, MAX_SFNT_SIZE, MAX_TABLES};
+
+// Tags encoded by their index in the table directory, index 63 means the tag follows
+const KNOWN_TAGS: [&[u8; 4]; 63] = [
+    b"cmap", b"head", b"hhea", b"hmtx", b"maxp", b"name", b"OS/2", b"post",
+    b"cvt ", b"fpgm", b"glyf", b"loca", b"prep", b"CFF ", b"VORG", b"EBDT",
+    b"EBLC", b"gasp", b"hdmx", b"kern", b"LTSH", b"PCLT", b"VDMX", b"vhea",
+    b"vmtx", b"BASE", b"GDEF", b"GPOS", b"GSUB", b"EBSC", b"JSTF", b"MATH",
+    b"CBDT", b"CBLC", b"COLR", b"CPAL", b"SVG ", b"sbix", b"acnt", b"avar",
+    b"bdat", b"bloc", b"bsln", b"cvar", b"fdsc", b"feat", b"fmtx", b"fvar",
+    b"gvar", b"hsty", b"just", b"lcar", b"mort", b"morx", b"opbd", b"prop",
+    b"trak", b"Zapf", b"Silf", b"Glat", b"Gloc", b"Feat", b"Sill",
+];
+
+const WOFF2_HEADER_SIZE: usize = 48;
+
+// Flags of TrueType simple glyph points
+const ON_CURVE_POINT: u8 = 0x01;
+const X_SHORT_VECTOR: u8 = 0x02;
+const Y_SHORT_VECTOR: u8 = 0x04;
+const X_IS_SAME_OR_POSITIVE: u8 = 0x10;
+const Y_IS_SAME_OR_POSITIVE: u8 = 0x20;
+const OVERLAP_SIMPLE: u8 = 0x40;
+
+// Flags of TrueType composite glyph components
+const ARG_1_AND_2_ARE_WORDS: u16 = 0x0001;
+const WE_HAVE_A_SCALE: u16 = 0x0008;
+const MORE_COMPONENTS: u16 = 0x0020;
+const WE_HAVE_AN_X_AND_Y_SCALE: u16 = 0x0040;
+const WE_HAVE_A_TWO_BY_TWO: u16 = 0x0080;
+const WE_HAVE_INSTRUCTIONS: u16 = 0x0100;
+
+struct Entry {
+    tag: [u8; 4],
+    transformed: bool,
+    // Length of the table in the decompressed stream
+    length: usize,
+}
+
+// WOFF2 compresses every table at once with Brotli. The glyf and loca tables, and
+// optionally hmtx, are stored in a transformed form that has to be rebuilt.
+pub(super) fn decode(data: &[u8]) -> Option<Vec<u8>> {
+    let mut stream = Stream::new(data);
+
+    stream.skip(4)?;
+    let flavor = stream.u32()?;
+    stream.skip(4)?;
+    let table_count = stream.u16()? as usize;
+
+    if table_count > MAX_TABLES {
+        return None;
+    }
+
+    stream.skip(6)?;
+    let compressed_size = stream.u32()? as usize;
+    stream.skip(WOFF2_HEADER_SIZE - stream.offset)?;
+
+    let mut entries = Vec::with_capacity(table_count);
+
+    for _ in 0..table_count {
+        let flags = stream.u8()?;
+
+        let tag = match flags & 0x3f {
+            63 => stream.tag()?,
+            index => *KNOWN_TAGS[index as usize],
+        };
+
+        // The null transform of glyf and loca is version 3, version 0 for other tables
+        let version = flags >> 6;
+        let transformed = match &tag {
+            b"glyf" | b"loca" => version == 0,
+            _ => version != 0,
+        };
+
+        let original_length = base128(&mut stream)? as usize;
+        let length = if transformed { base128(&mut stream)? as usize } else { original_length };
+
+        entries.push(Entry { tag, transformed, length });
+    }
+
+    let fonts = if flavor == u32::from_be_bytes(*b"ttcf") {
+        stream.skip(4)?;
+        let font_count = u255(&mut stream)?;
+
+        (0..font_count).map(|_| {
+            let count = u255(&mut stream).map(usize::from).filter(|count| *count <= MAX_TABLES)?;
+            let flavor = stream.u32()?;
+
+            let indices = (0..count)
+                .map(|_| u255(&mut stream).map(usize::from).filter(|index| *index < entries.len()))
+                .collect::<Option<Vec<_>>>()?;
+
+            Some((flavor, indices))
+        }).collect::<Option<Vec<_>>>()?
+    } else {
+        vec![(flavor, (0..entries.len()).collect())]
+    };
+
+    let total_length = entries.iter().try_fold(0usize, |total, entry| total.checked_add(entry.length))?;
+
+    if total_length > MAX_SFNT_SIZE {
+        return None;
+    }
+
+    let compressed = stream.bytes(compressed_size)?;
+    let mut decompressed = Vec::with_capacity(total_length);
+
+    brotli_decompressor::Decompressor::new(compressed, 4096)
+        .take(total_length as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .ok()?;
+
+    if decompressed.len() != total_length {
+        return None;
+    }
+
+    let mut tables: Vec<Table> = Vec::with_capacity(entries.len());
+    let mut offset = 0;
+
+    for entry in &entries {
+        tables.push(Table {
+            tag: entry.tag,
+            data: decompressed[offset..offset + entry.length].to_vec(),
+        });
+
+        offset += entry.length;
+    }
+
+    // The loca table always follows its glyf table, the horizontal bearings of hmtx
+    // can be left out as they match the left of the glyph bounding boxes
+    let mut glyph_x_mins: HashMap<usize, Vec<i16>> = HashMap::new();
+
+    for (i, entry) in entries.iter().enumerate() {
+        if &entry.tag != b"glyf" || !entry.transformed {
+            continue;
+        }
+
+        if entries.get(i + 1).map(|entry| &entry.tag) != Some(b"loca") {
+            return None;
+        }
+
+        let (glyf, loca, x_mins) = reconstruct_glyf(&tables[i].data)?;
+
+        tables[i].data = glyf;
+        tables[i + 1].data = loca;
+        glyph_x_mins.insert(i, x_mins);
+    }
+
+    for (_, indices) in &fonts {
+        let find = |tag: &[u8; 4]| indices.iter().copied().find(|index| &entries[*index].tag == tag);
+
+        let Some(hmtx) = find(b"hmtx").filter(|index| entries[*index].transformed) else {
+            continue;
+        };
+
+        let x_mins = find(b"glyf").and_then(|glyf| glyph_x_mins.get(&glyf))?;
+        let glyph_count = Stream::new(tables.get(find(b"maxp")?)?.data.get(4..)?).u16()? as usize;
+        let metric_count = Stream::new(tables.get(find(b"hhea")?)?.data.get(34..)?).u16()? as usize;
+
+        tables[hmtx].data = reconstruct_hmtx(&tables[hmtx].data, glyph_count, metric_count, x_mins)?;
+    }
+
+    Some(write_sfnt(&fonts, &tables))
+}
+
+// Rebuild the glyf and loca tables from the streams of the transformed glyf table.
+// The left of every glyph bounding box is returned for the hmtx transform.
+fn reconstruct_glyf(data: &[u8]) -> Option<(Vec<u8>, Vec<u8>, Vec<i16>)> {
+    let mut header = Stream::new(data);
+
+    header.skip(2)?;
+    let options = header.u16()?;
+    let glyph_count = header.u16()? as usize;
+    let index_format = header.u16()?;
+
+    let mut sizes = [0; 7];
+
+    for size in &mut sizes {
+        *size = header.u32()? as usize;
+    }
+
+    let mut streams = Vec::with_capacity(sizes.len());
+
+    for size in sizes {
+        streams.push(Stream::new(header.bytes(size)?));
+    }
+
+    let [mut contour_counts, mut point_counts, mut flags, mut glyphs, mut composites, mut bboxes, mut instructions]: [Stream; 7] = streams.try_into().ok()?;
+
+    let bbox_bitmap = bboxes.bytes(((glyph_count + 31) >> 5) << 2)?;
+
+    let overlap_bitmap = match options & 1 {
+        0 => None,
+        _ => Some(header.bytes((glyph_count + 7) >> 3)?),
+    };
+
+    let bit = |bitmap: &[u8], i: usize| bitmap[i >> 3] & (0x80 >> (i & 7)) != 0;
+
+    let mut glyf = Vec::new();
+    let mut offsets = Vec::with_capacity(glyph_count + 1);
+    let mut x_mins = Vec::with_capacity(glyph_count);
+
+    for i in 0..glyph_count {
+        offsets.push(glyf.len());
+
+        let contour_count = contour_counts.i16()?;
+        let has_bbox = bit(bbox_bitmap, i);
+
+        match contour_count {
+            0 => {
+                if has_bbox {
+                    return None;
+                }
+
+                x_mins.push(0);
+            }
+            -1 => {
+                // Components are stored as is, only their length has to be found
+                let start = composites.offset;
+                let mut has_instructions = false;
+
+                loop {
+                    let flags = composites.u16()?;
+
+                    let arguments = if flags & ARG_1_AND_2_ARE_WORDS != 0 { 4 } else { 2 };
+                    let scale = if flags & WE_HAVE_A_SCALE != 0 {
+                        2
+                    } else if flags & WE_HAVE_AN_X_AND_Y_SCALE != 0 {
+                        4
+                    } else if flags & WE_HAVE_A_TWO_BY_TWO != 0 {
+                        8
+                    } else {
+                        0
+                    };
+
+                    composites.skip(2 + arguments + scale)?;
+                    has_instructions |= flags & WE_HAVE_INSTRUCTIONS != 0;
+
+                    if flags & MORE_COMPONENTS == 0 {
+                        break;
+                    }
+                }
+
+                // Composite glyphs always have an explicit bounding box
+                if !has_bbox {
+                    return None;
+                }
+
+                let bbox = [bboxes.i16()?, bboxes.i16()?, bboxes.i16()?, bboxes.i16()?];
+
+                glyf.extend_from_slice(&(-1i16).to_be_bytes());
+                bbox.iter().for_each(|value| glyf.extend_from_slice(&value.to_be_bytes()));
+                glyf.extend_from_slice(&composites.data[start..composites.offset]);
+
+                if has_instructions {
+                    let length = u255(&mut glyphs)?;
+
+                    glyf.extend_from_slice(&length.to_be_bytes());
+                    glyf.extend_from_slice(instructions.bytes(length as usize)?);
+                }
+
+                x_mins.push(bbox[0]);
+            }
+            contour_count if contour_count > 0 => {
+                let mut end_points = Vec::with_capacity(contour_count as usize);
+                let mut point_count = 0usize;
+
+                for _ in 0..contour_count {
+                    point_count += u255(&mut point_counts)? as usize;
+
+                    end_points.push(u16::try_from(point_count.checked_sub(1)?).ok()?);
+                }
+
+                let mut points = Vec::with_capacity(point_count);
+                let [mut x, mut y] = [0i32, 0i32];
+
+                for _ in 0..point_count {
+                    let flag = flags.u8()?;
+                    let [dx, dy] = triplet(flag & 0x7f, &mut glyphs)?;
+
+                    x += dx;
+                    y += dy;
+
+                    points.push((x, y, flag & 0x80 == 0));
+                }
+
+                let instruction_length = u255(&mut glyphs)?;
+
+                let bbox = if has_bbox {
+                    [bboxes.i16()?, bboxes.i16()?, bboxes.i16()?, bboxes.i16()?]
+                } else {
+                    points.iter().fold([i16::MAX, i16::MAX, i16::MIN, i16::MIN], |[x_min, y_min, x_max, y_max], (x, y, _)| {
+                        let (x, y) = (*x as i16, *y as i16);
+
+                        [x_min.min(x), y_min.min(y), x_max.max(x), y_max.max(y)]
+                    })
+                };
+
+                glyf.extend_from_slice(&contour_count.to_be_bytes());
+                bbox.iter().for_each(|value| glyf.extend_from_slice(&value.to_be_bytes()));
+                end_points.iter().for_each(|end_point| glyf.extend_from_slice(&end_point.to_be_bytes()));
+                glyf.extend_from_slice(&instruction_length.to_be_bytes());
+                glyf.extend_from_slice(instructions.bytes(instruction_length as usize)?);
+
+                let overlap = overlap_bitmap.is_some_and(|bitmap| bit(bitmap, i));
+
+                write_points(&mut glyf, &points, overlap);
+
+                x_mins.push(bbox[0]);
+            }
+            _ => return None,
+        }
+
+        glyf.resize(glyf.len().next_multiple_of(4), 0);
+    }
+
+    offsets.push(glyf.len());
+
+    let loca = match index_format {
+        0 => offsets.iter()
+            .map(|offset| u16::try_from(offset / 2).ok().map(u16::to_be_bytes))
+            .collect::<Option<Vec<_>>>()?
+            .concat(),
+        _ => offsets.iter()
+            .map(|offset| u32::try_from(*offset).ok().map(u32::to_be_bytes))
+            .collect::<Option<Vec<_>>>()?
+            .concat(),
+    };
+
+    Some((glyf, loca, x_mins))
+}
+
+// Encode the points of a simple glyph as flags, then x and y deltas
+fn write_points(glyf: &mut Vec<u8>, points: &[(i32, i32, bool)], overlap: bool) {
+    let mut flags = Vec::with_capacity(points.len());
+    let mut xs = Vec::with_capacity(points.len() * 2);
+    let mut ys = Vec::with_capacity(points.len() * 2);
+
+    let [mut last_x, mut last_y] = [0, 0];
+
+    for (i, (x, y, on_curve)) in points.iter().enumerate() {
+        let mut flag = if *on_curve { ON_CURVE_POINT } else { 0 };
+
+        if i == 0 && overlap {
+            flag |= OVERLAP_SIMPLE;
+        }
+
+        for (delta, coordinates, short, same_or_positive) in [
+            (x - last_x, &mut xs, X_SHORT_VECTOR, X_IS_SAME_OR_POSITIVE),
+            (y - last_y, &mut ys, Y_SHORT_VECTOR, Y_IS_SAME_OR_POSITIVE),
+        ] {
+            if delta == 0 {
+                flag |= same_or_positive;
+            } else if delta.abs() < 256 {
+                flag |= short;
+
+                if delta > 0 {
+                    flag |= same_or_positive;
+                }
+
+                coordinates.push(delta.unsigned_abs() as u8);
+            } else {
+                coordinates.extend_from_slice(&(delta as i16).to_be_bytes());
+            }
+        }
+
+        flags.push(flag);
+        [last_x, last_y] = [*x, *y];
+    }
+
+    glyf.extend_from_slice(&flags);
+    glyf.extend_from_slice(&xs);
+    glyf.extend_from_slice(&ys);
+}
+
+// Rebuild hmtx from the advances and the bearings that weren't left out
+fn reconstruct_hmtx(data: &[u8], glyph_count: usize, metric_count: usize, x_mins: &[i16]) -> Option<Vec<u8>> {
+    if metric_count == 0 || metric_count > glyph_count || x_mins.len() < glyph_count {
+        return None;
+    }
+
+    let mut stream = Stream::new(data);
+
+    let flags = stream.u8()?;
+
+    let advances = (0..metric_count).map(|_| stream.u16()).collect::<Option<Vec<_>>>()?;
+
+    let proportional = match flags & 1 {
+        0 => (0..metric_count).map(|_| stream.i16()).collect::<Option<Vec<_>>>()?,
+        _ => x_mins[..metric_count].to_vec(),
+    };
+
+    let monospaced = match flags & 2 {
+        0 => (metric_count..glyph_count).map(|_| stream.i16()).collect::<Option<Vec<_>>>()?,
+        _ => x_mins[metric_count..glyph_count].to_vec(),
+    };
+
+    let mut hmtx = Vec::with_capacity(metric_count * 4 + monospaced.len() * 2);
+
+    for (advance, bearing) in advances.iter().zip(&proportional) {
+        hmtx.extend_from_slice(&advance.to_be_bytes());
+        hmtx.extend_from_slice(&bearing.to_be_bytes());
+    }
+
+    for bearing in &monospaced {
+        hmtx.extend_from_slice(&bearing.to_be_bytes());
+    }
+
+    Some(hmtx)
+}
+
+// Point deltas are packed in 1 to 4 bytes, the flag gives the layout and the signs
+fn triplet(flag: u8, glyphs: &mut Stream) -> Option<[i32; 2]> {
+    let with_sign = |flag: u8, value: i32| if flag & 1 != 0 { value } else { -value };
+    let flag_value = flag as i32;
+
+    let delta = match flag {
+        0..=9 => {
+            let b0 = glyphs.u8()? as i32;
+
+            [0, with_sign(flag, ((flag_value & 14) << 7) + b0)]
+        }
+        10..=19 => {
+            let b0 = glyphs.u8()? as i32;
+
+            [with_sign(flag, (((flag_value - 10) & 14) << 7) + b0), 0]
+        }
+        20..=83 => {
+            let (b0, b1) = (flag_value - 20, glyphs.u8()? as i32);
+
+            [
+                with_sign(flag, 1 + (b0 & 0x30) + (b1 >> 4)),
+                with_sign(flag >> 1, 1 + ((b0 & 0x0c) << 2) + (b1 & 0x0f)),
+            ]
+        }
+        84..=119 => {
+            let (b0, b1, b2) = (flag_value - 84, glyphs.u8()? as i32, glyphs.u8()? as i32);
+
+            [
+                with_sign(flag, 1 + ((b0 / 12) << 8) + b1),
+                with_sign(flag >> 1, 1 + (((b0 % 12) >> 2) << 8) + b2),
+            ]
+        }
+        120..=123 => {
+            let (b1, b2, b3) = (glyphs.u8()? as i32, glyphs.u8()? as i32, glyphs.u8()? as i32);
+
+            [
+                with_sign(flag, (b1 << 4) + (b2 >> 4)),
+                with_sign(flag >> 1, ((b2 & 0x0f) << 8) + b3),
+            ]
+        }
+        _ => {
+            let (b1, b2, b3, b4) = (glyphs.u8()? as i32, glyphs.u8()? as i32, glyphs.u8()? as i32, glyphs.u8()? as i32);
+
+            [
+                with_sign(flag, (b1 << 8) + b2),
+                with_sign(flag >> 1, (b3 << 8) + b4),
+            ]
+        }
+    };
+
+    Some(delta)
+}
+
+// Variable length integer of up to 5 bytes, 7 bits per byte
+fn base128(stream: &mut Stream) -> Option<u32> {
+    let mut value = 0u32;
+
+    for i in 0..5 {
+        let byte = stream.u8()?;
+
+        // Leading zeros aren't allowed
+        if i == 0 && byte == 0x80 {
+            return None;
+        }
+
+        if value & 0xfe000000 != 0 {
+            return None;
+        }
+
+        value = (value << 7) | (byte & 0x7f) as u32;
+
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+// Variable length integer of 1 to 3 bytes, used for small counts
+fn u255(stream: &mut Stream) -> Option<u16> {
+    match stream.u8()? {
+        253 => stream.u16(),
+        254 => Some(stream.u8()? as u16 + 253 * 2),
+        255 => Some(stream.u8()? as u16 + 253),
+        code => Some(code as u16),
+    }
+}